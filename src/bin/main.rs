@@ -1,13 +1,30 @@
-use clap::Parser;
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use clap::{parser::ValueSource, ArgAction, CommandFactory, FromArgMatches, Parser, Subcommand};
 
 use candle_core::Device;
 use futures::StreamExt;
+use rustyline::{error::ReadlineError, DefaultEditor};
+use serde::Serialize;
+use tokenizers::Tokenizer;
 use wavvy_ai_sdk::{
-    llm::{model_builder::ModelBuilder, wavvy_chat::WavvyChat, wavvy_chat_stream::WavvyArgs},
+    config::WavvyConfig,
+    llm::{
+        kv_cache::KvCacheDType,
+        latency::percentile,
+        model_builder::ModelBuilder,
+        quantized_qwen2::ModelWeights,
+        scoring::Scorer,
+        wavvy_chat::WavvyChat,
+        wavvy_chat_stream::{ChatResponse, FinishReason, WavvyArgs, WavvyError},
+    },
     prompt_template::{
         chat_template::{ChatTemplate, Model},
         message::Message,
         role::Role,
+        truncation::TruncationStrategy,
     },
 };
 
@@ -24,16 +41,34 @@ struct Args {
     #[arg(long)]
     pub model_path: Option<String>,
 
+    #[arg(
+        long,
+        help = "TOML or JSON file with sampling presets and model definitions"
+    )]
+    pub config: Option<String>,
+
+    #[arg(
+        long = "model",
+        help = "Model definition from --config, used in place of the paths"
+    )]
+    pub model_definition: Option<String>,
+
+    #[arg(
+        long,
+        help = "Sampling preset from --config, flags given explicitly override it"
+    )]
+    pub preset: Option<String>,
+
     #[arg(long)]
     pub tokenizer_path: Option<String>,
 
     #[arg(long)]
     pub prompt: Option<String>,
 
-    #[arg(short = 'n', long, default_value_t = 1000)]
+    #[arg(short = 'n', long, default_value_t = WavvyArgs::default().sample_len)]
     pub sample_len: usize,
 
-    #[arg(long, default_value_t = 0.8)]
+    #[arg(long, default_value_t = WavvyArgs::default().temperature)]
     pub temperature: f64,
 
     #[arg(long)]
@@ -42,36 +77,575 @@ struct Args {
     #[arg(long)]
     pub top_k: Option<usize>,
 
-    #[arg(long, default_value_t = 299792458)]
+    #[arg(long, default_value_t = WavvyArgs::default().seed)]
     pub seed: u64,
 
-    #[arg(long)]
+    #[arg(
+        long,
+        help = "Prefill the prompt one token at a time, --split-prompt=false runs it at once",
+        default_value_t = WavvyArgs::default().split_prompt,
+        num_args = 0..=1,
+        default_missing_value = "true",
+        action = ArgAction::Set
+    )]
     pub split_prompt: bool,
 
-    #[arg(long, default_value_t = 1.1)]
+    #[arg(long, default_value_t = WavvyArgs::default().repeat_penalty)]
     pub repeat_penalty: f32,
 
-    #[arg(long, default_value_t = 64)]
+    #[arg(long, default_value_t = WavvyArgs::default().repeat_last_n)]
     pub repeat_last_n: usize,
+
+    #[arg(
+        long,
+        help = "DRY penalty multiplier, 0 disables it",
+        default_value_t = WavvyArgs::default().dry_multiplier
+    )]
+    pub dry_multiplier: f32,
+
+    #[arg(long, default_value_t = WavvyArgs::default().dry_base)]
+    pub dry_base: f32,
+
+    #[arg(long, default_value_t = WavvyArgs::default().dry_allowed_length)]
+    pub dry_allowed_length: usize,
+
+    #[arg(
+        long,
+        help = "Tokens scanned by DRY, 0 means the whole context",
+        default_value_t = WavvyArgs::default().dry_penalty_last_n
+    )]
+    pub dry_penalty_last_n: usize,
+
+    #[arg(
+        long = "dry-sequence-breaker",
+        default_values_t = WavvyArgs::default().dry_sequence_breakers,
+    )]
+    pub dry_sequence_breakers: Vec<String>,
+
+    #[arg(
+        long,
+        help = "Bias added to a token logit, as TOKEN_ID=BIAS",
+        value_parser = parse_logit_bias
+    )]
+    pub logit_bias: Vec<(u32, f32)>,
+
+    #[arg(long = "banned-string", help = "Text the answer must never contain")]
+    pub banned_strings: Vec<String>,
+
+    #[arg(long, help = "Small model drafting tokens for speculative decoding")]
+    pub draft_model_path: Option<String>,
+
+    #[arg(long, default_value_t = WavvyArgs::default().num_draft_tokens)]
+    pub num_draft_tokens: usize,
+
+    #[arg(
+        long,
+        help = "Tokens proposed by prompt lookup per step, 0 disables it",
+        default_value_t = WavvyArgs::default().prompt_lookup_num_tokens
+    )]
+    pub prompt_lookup_num_tokens: usize,
+
+    #[arg(long, default_value_t = WavvyArgs::default().prompt_lookup_max_ngram)]
+    pub prompt_lookup_max_ngram: usize,
+
+    #[arg(
+        long,
+        help = "Beam search width, 1 samples instead",
+        default_value_t = WavvyArgs::default().num_beams
+    )]
+    pub num_beams: usize,
+
+    #[arg(long, default_value_t = WavvyArgs::default().length_penalty)]
+    pub length_penalty: f32,
+
+    #[arg(long)]
+    pub early_stopping: bool,
+
+    #[arg(
+        long,
+        help = "Completions returned for the prompt",
+        default_value_t = WavvyArgs::default().n
+    )]
+    pub n: usize,
+
+    #[arg(
+        long,
+        help = "Completions sampled to pick the n most likely from, defaults to n"
+    )]
+    pub best_of: Option<usize>,
+
+    #[arg(long, help = "Stop generating after this many seconds")]
+    pub max_duration: Option<f64>,
+
+    #[arg(
+        long,
+        help = "Shorten prompts that do not fit: drop-oldest, keep-last=N or middle",
+        value_parser = parse_truncation
+    )]
+    pub truncation: Option<TruncationStrategy>,
+
+    #[arg(
+        long,
+        help = "Keep generating past the context length by dropping old tokens"
+    )]
+    pub context_shift: bool,
+
+    #[arg(
+        long,
+        help = "Tokens kept at the start of the context when it shifts",
+        default_value_t = WavvyArgs::default().sink_tokens
+    )]
+    pub sink_tokens: usize,
+
+    #[arg(
+        long,
+        help = "Storage of the KV cache: f32, f16 or q8_0",
+        value_parser = parse_kv_cache_dtype,
+        default_value_t = WavvyArgs::default().kv_cache_dtype
+    )]
+    pub kv_cache_dtype: KvCacheDType,
+
+    #[arg(long, help = "Tokens of KV cache allocated up front")]
+    pub kv_cache_capacity: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Prompt tokens per prefill forward, overrides --split-prompt"
+    )]
+    pub prefill_chunk_size: Option<usize>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
 }
 
-#[tokio::main]
-async fn main() {
-    let args = Args::parse();
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Perplexity of a text file, scored with a sliding window
+    Perplexity {
+        #[arg(long)]
+        file: String,
 
-    let device = Device::new_metal(0).unwrap();
+        #[arg(long, default_value_t = 512, help = "Tokens the model sees at once")]
+        window: usize,
 
-    let question = args.prompt.unwrap();
+        #[arg(
+            long,
+            default_value_t = 256,
+            help = "Tokens the window moves by, less than the window"
+        )]
+        stride: usize,
+    },
+    /// Load, prefill and decode speed over repeated generations
+    Bench {
+        #[arg(long, default_value_t = 512)]
+        prompt_tokens: usize,
 
-    let model_name = if args.model_name == "r1" {
-        Model::R1
-    } else {
-        Model::W
-    };
+        #[arg(long, default_value_t = 128)]
+        gen_tokens: usize,
 
-    let messages = vec![Message::new(Role::User, question.clone())];
+        #[arg(long, default_value_t = 5)]
+        repetitions: usize,
+
+        #[arg(long, default_value_t = 1, help = "Runs left out of the results")]
+        warmup: usize,
 
-    let message_template = ChatTemplate::new(model_name, messages);
+        #[arg(long, help = "Print the results as JSON")]
+        json: bool,
+    },
+    /// Multi-turn conversation, /help lists the commands
+    Chat {
+        #[arg(long, help = "System prompt of the conversation")]
+        system: Option<String>,
+
+        #[arg(long, help = "File the input history is kept in across sessions")]
+        history_file: Option<String>,
+    },
+}
+
+#[derive(Serialize, Debug, Default)]
+struct Summary {
+    mean: f64,
+    p50: f64,
+    p95: f64,
+}
+
+impl Summary {
+    fn new(values: &[f64]) -> Self {
+        if values.is_empty() {
+            return Self::default();
+        }
+        let mut sorted = values.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        Self {
+            mean: values.iter().sum::<f64>() / values.len() as f64,
+            p50: percentile(&sorted, 0.5),
+            p95: percentile(&sorted, 0.95),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct BenchReport {
+    model_path: String,
+    load_seconds: f64,
+    prompt_tokens: usize,
+    gen_tokens: usize,
+    repetitions: usize,
+    prefill_mode: String,
+    time_to_first_token_seconds: Summary,
+    prefill_tokens_per_second: Summary,
+    decode_tokens_per_second: Summary,
+}
+
+/// How `args` prefill the prompt, the way `WavvyChatStream` picks the chunk size.
+fn prefill_mode(args: &WavvyArgs) -> String {
+    match args.prefill_chunk_size {
+        Some(chunk_size) => format!("chunks of {} tokens", chunk_size.max(1)),
+        None if args.split_prompt => String::from("one token at a time"),
+        None => String::from("whole prompt"),
+    }
+}
+
+struct BenchRun {
+    prompt_tokens: usize,
+    time_to_first_token: Duration,
+    prefill_time: Duration,
+    decode_tokens: usize,
+    decode_time: Duration,
+}
+
+/// One generation, the end of sequence token is banned so that it runs for
+/// the full `sample_len`.
+fn bench_run(
+    mut wavvy: WavvyChat,
+    eos_token: Option<u32>,
+    prompt: String,
+) -> Result<BenchRun, WavvyError> {
+    if let Some(eos_token) = eos_token {
+        wavvy.args.logit_bias.insert(eos_token, f32::NEG_INFINITY);
+    }
+    let mut response = ChatResponse::default();
+    for item in wavvy.iter_invoke(prompt)? {
+        response = item?;
+    }
+    let latency = response.latency.unwrap_or_default();
+    Ok(BenchRun {
+        prompt_tokens: response.prompt_tokens,
+        time_to_first_token: latency.time_to_first_token,
+        prefill_time: latency.prefill,
+        decode_tokens: response.completion_tokens.saturating_sub(1),
+        decode_time: latency.decode,
+    })
+}
+
+const CHAT_HELP: &str = "\
+/system TEXT       replace the system prompt
+/reset             start over, keeping the system prompt
+/save PATH         write the conversation to a JSON file
+/load PATH         read a conversation written by /save
+/set NAME VALUE    change a sampling setting, e.g. /set temperature 0.2
+/help              show this help
+/quit              leave, like Ctrl-D
+
+Ctrl-C stops the answer being generated.";
+
+struct ChatSession {
+    model_name: Model,
+    model: ModelWeights,
+    tokenizer: Tokenizer,
+    device: Device,
+    args: WavvyArgs,
+    truncation: TruncationStrategy,
+}
+
+impl ChatSession {
+    async fn run(mut self, system: Option<String>, history_file: Option<&str>) {
+        let mut editor = DefaultEditor::new().unwrap();
+        if let Some(history_file) = history_file {
+            // A missing file only means there is no history yet.
+            let _ = editor.load_history(history_file);
+        }
+        let mut template = ChatTemplate::new(self.model_name.clone(), vec![]);
+        if let Some(system) = system {
+            set_system_prompt(&mut template, system);
+        }
+
+        println!("Chat started, /help lists the commands");
+        loop {
+            let line = match editor.readline(">>> ") {
+                Ok(line) => line,
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => break,
+                Err(e) => {
+                    println!("Error: {e}");
+                    break;
+                }
+            };
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let _ = editor.add_history_entry(line);
+
+            let Some(command) = line.strip_prefix('/') else {
+                template
+                    .messages
+                    .push(Message::new(Role::User, line.to_string()));
+                self.answer(&mut template).await;
+                continue;
+            };
+            let (command, rest) = command.split_once(' ').unwrap_or((command, ""));
+            let rest = rest.trim();
+            match command {
+                "system" => {
+                    set_system_prompt(&mut template, rest.to_string());
+                    println!("System prompt set");
+                }
+                "reset" => {
+                    template
+                        .messages
+                        .retain(|message| matches!(message.role, Role::System));
+                    println!("Conversation cleared");
+                }
+                "save" => match save_template(&template, rest) {
+                    Ok(()) => println!("Saved {} messages to {rest}", template.messages.len()),
+                    Err(e) => println!("Error: {e}"),
+                },
+                "load" => match load_template(rest) {
+                    Ok(loaded) => {
+                        template.messages = loaded.messages;
+                        println!("Loaded {} messages from {rest}", template.messages.len());
+                    }
+                    Err(e) => println!("Error: {e}"),
+                },
+                "set" => {
+                    let (name, value) = rest.split_once(' ').unwrap_or((rest, ""));
+                    match set_arg(&self.args, name, value.trim()) {
+                        Ok(args) => {
+                            self.args = args;
+                            println!("{name} set to {}", value.trim());
+                        }
+                        Err(e) => println!("Error: {e}"),
+                    }
+                }
+                "help" => println!("{CHAT_HELP}"),
+                "quit" | "exit" => break,
+                _ => println!("Unknown command /{command}, /help lists the commands"),
+            }
+        }
+
+        if let Some(history_file) = history_file {
+            if let Err(e) = editor.save_history(history_file) {
+                println!("Error: {e}");
+            }
+        }
+    }
+
+    /// Generates the next assistant message, which is kept even when Ctrl-C
+    /// stops it early.
+    async fn answer(&self, template: &mut ChatTemplate) {
+        let max_prompt_tokens = self
+            .model
+            .context_length()
+            .saturating_sub(self.args.sample_len);
+        if let Err(e) = template.truncate(&self.tokenizer, max_prompt_tokens, &self.truncation) {
+            println!("Error: {e}");
+            drop_unanswered(template);
+            return;
+        }
+
+        let wavvy = WavvyChat::new(
+            self.model_name.clone(),
+            self.model.clone(),
+            self.tokenizer.clone(),
+            &self.device,
+            Some(self.args.clone()),
+        );
+        let mut response = match wavvy.stream_invoke(template.format()) {
+            Ok(response) => response,
+            Err(e) => {
+                println!("Error: {e}");
+                drop_unanswered(template);
+                return;
+            }
+        };
+
+        // Only the first choice is shown and kept when the settings ask for several.
+        let mut answer = ChatResponse::default();
+        let ctrl_c = tokio::signal::ctrl_c();
+        tokio::pin!(ctrl_c);
+        let mut interrupted = false;
+        let mut failed = false;
+        loop {
+            tokio::select! {
+                item = response.next() => match item {
+                    Some(Ok(chunk)) if chunk.index == 0 => {
+                        print!("{}", chunk.content);
+                        let _ = std::io::stdout().flush();
+                        answer.merge(chunk);
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => {
+                        println!("\nError: {e}");
+                        failed = true;
+                    }
+                    None => break,
+                },
+                _ = &mut ctrl_c, if !interrupted => {
+                    interrupted = true;
+                    response.cancel();
+                }
+            }
+        }
+        println!();
+
+        if failed || answer.content.is_empty() {
+            drop_unanswered(template);
+            return;
+        }
+
+        let latency = answer.latency.unwrap_or_default();
+        // A single token answer has no decode time to measure a rate over.
+        let tokens_per_second = match latency.decode.as_secs_f64() {
+            seconds if seconds > 0. => format!(
+                "{:.1}",
+                answer.completion_tokens.saturating_sub(1) as f64 / seconds
+            ),
+            _ => String::from("-"),
+        };
+        println!(
+            "[{} prompt tokens, {} completion tokens, first token {:.2}s, {} tokens/s, {:?}]",
+            answer.prompt_tokens,
+            answer.completion_tokens,
+            latency.time_to_first_token.as_secs_f64(),
+            tokens_per_second,
+            answer.finish_reason.unwrap_or(FinishReason::Stop)
+        );
+        template
+            .messages
+            .push(Message::new(Role::Assistant, answer.content));
+    }
+}
+
+/// Removes the last question when it got no answer, it would otherwise be
+/// asked again next turn.
+fn drop_unanswered(template: &mut ChatTemplate) {
+    if template
+        .messages
+        .last()
+        .is_some_and(|message| matches!(message.role, Role::User))
+    {
+        template.messages.pop();
+    }
+}
+
+fn set_system_prompt(template: &mut ChatTemplate, system: String) {
+    template
+        .messages
+        .retain(|message| !matches!(message.role, Role::System));
+    template
+        .messages
+        .insert(0, Message::new(Role::System, system));
+}
+
+fn save_template(template: &ChatTemplate, path: &str) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(template).map_err(|e| e.to_string())?;
+    std::fs::write(path, json).map_err(|e| e.to_string())
+}
+
+fn load_template(path: &str) -> Result<ChatTemplate, String> {
+    let json = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    serde_json::from_str(&json).map_err(|e| e.to_string())
+}
+
+/// Sets one field of `args` by its serialized name. The value is read as JSON,
+/// falling back to a plain string.
+fn set_arg(args: &WavvyArgs, name: &str, value: &str) -> Result<WavvyArgs, String> {
+    let mut fields = serde_json::to_value(args).map_err(|e| e.to_string())?;
+    let field = fields
+        .get_mut(name)
+        .ok_or_else(|| format!("unknown setting '{name}'"))?;
+    *field = serde_json::from_str(value)
+        .unwrap_or_else(|_| serde_json::Value::String(value.to_string()));
+    let args: WavvyArgs = serde_json::from_value(fields).map_err(|e| e.to_string())?;
+    args.validate().map_err(|e| e.to_string())?;
+    Ok(args)
+}
+
+fn parse_kv_cache_dtype(s: &str) -> Result<KvCacheDType, String> {
+    match s {
+        "f32" => Ok(KvCacheDType::F32),
+        "f16" => Ok(KvCacheDType::F16),
+        "q8_0" => Ok(KvCacheDType::Q8_0),
+        _ => Err(format!("expected f32, f16 or q8_0, got '{s}'")),
+    }
+}
+
+fn parse_truncation(s: &str) -> Result<TruncationStrategy, String> {
+    match s.split_once('=') {
+        None if s == "drop-oldest" => Ok(TruncationStrategy::DropOldest),
+        None if s == "middle" => Ok(TruncationStrategy::TruncateMiddle),
+        Some(("keep-last", n)) => n
+            .trim()
+            .parse::<usize>()
+            .map(TruncationStrategy::KeepSystemAndLast)
+            .map_err(|e| e.to_string()),
+        _ => Err(format!(
+            "expected drop-oldest, keep-last=N or middle, got '{s}'"
+        )),
+    }
+}
+
+fn parse_logit_bias(s: &str) -> Result<(u32, f32), String> {
+    let (token, bias) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected TOKEN_ID=BIAS, got '{s}'"))?;
+    let token = token.trim().parse::<u32>().map_err(|e| e.to_string())?;
+    let bias = bias.trim().parse::<f32>().map_err(|e| e.to_string())?;
+    Ok((token, bias))
+}
+
+#[tokio::main]
+async fn main() {
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap();
+    let explicit = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+    let config = args
+        .config
+        .as_ref()
+        .map(|path| WavvyConfig::from_file(path).unwrap());
+    let mut preset_name = args.preset.clone();
+    if let Some(name) = &args.model_definition {
+        let definition = config
+            .as_ref()
+            .expect("--model needs --config")
+            .model(name)
+            .unwrap();
+        args.model_path = args.model_path.or(Some(definition.model_path.clone()));
+        args.tokenizer_path = args
+            .tokenizer_path
+            .or(Some(definition.tokenizer_path.clone()));
+        args.draft_model_path = args
+            .draft_model_path
+            .or(definition.draft_model_path.clone());
+        if !explicit("model_name") && definition.template == Model::R1 {
+            args.model_name = String::from("r1");
+        }
+        preset_name = preset_name.or(definition.preset.clone());
+    }
+    let preset = preset_name.map(|name| {
+        config
+            .as_ref()
+            .expect("--preset needs --config")
+            .preset(&name)
+            .unwrap()
+    });
+
+    #[cfg(feature = "otel")]
+    let _telemetry = wavvy_ai_sdk::telemetry::init("wavvy").unwrap();
+
+    let device = Device::new_metal(0).unwrap();
 
     // Path examples:
     // model-path: ./model/Qwen2.5-3B-Instruct/qwen2.5-3b-instruct-q4_0.gguf
@@ -82,20 +656,228 @@ async fn main() {
         &device,
     );
 
-    let tokenizer = model_builder.load_tokenizer();
-    let model = model_builder.load_model();
+    let load_start = Instant::now();
+    let tokenizer = model_builder.load_tokenizer().unwrap();
+    let model = model_builder.load_model().unwrap();
+    let load_time = load_start.elapsed();
     println!("Model and tokenizer loaded");
 
-    let wavvy_args = Some(WavvyArgs {
+    if let Some(Command::Perplexity {
+        file,
+        window,
+        stride,
+    }) = &args.command
+    {
+        let text = std::fs::read_to_string(file).unwrap();
+        let mut scorer = Scorer::new(model, tokenizer, &device);
+        let time_process = Instant::now();
+        let score = scorer.perplexity(&text, *window, *stride).unwrap();
+        println!("file: {file}");
+        println!("tokens: {}", score.tokens.len());
+        println!("log_prob: {:.4}", score.log_prob);
+        println!("perplexity: {:.4}", score.perplexity());
+        println!(
+            "total_time: {:.2} seconds",
+            time_process.elapsed().as_secs_f64()
+        );
+        return;
+    }
+
+    if let Some(Command::Bench {
+        prompt_tokens,
+        gen_tokens,
+        repetitions,
+        warmup,
+        json,
+    }) = &args.command
+    {
+        let model_name = if args.model_name == "r1" {
+            Model::R1
+        } else {
+            Model::W
+        };
+        let eos_token = tokenizer.token_to_id(model_name.eos_token());
+        let bench_args = WavvyArgs {
+            sample_len: *gen_tokens,
+            seed: args.seed,
+            split_prompt: args.split_prompt,
+            prefill_chunk_size: args.prefill_chunk_size,
+            kv_cache_dtype: args.kv_cache_dtype,
+            kv_cache_capacity: args.kv_cache_capacity,
+            ..Default::default()
+        };
+        let prompt = "hello ".repeat(*prompt_tokens);
+
+        let mut runs = vec![];
+        for run in 0..warmup + repetitions {
+            let wavvy = WavvyChat::new(
+                model_name.clone(),
+                model.clone(),
+                tokenizer.clone(),
+                &device,
+                Some(bench_args.clone()),
+            );
+            let result = bench_run(wavvy, eos_token, prompt.clone()).unwrap();
+            if run >= *warmup {
+                runs.push(result);
+            }
+        }
+
+        let tokens_per_second = |tokens: usize, time: Duration| tokens as f64 / time.as_secs_f64();
+        let report = BenchReport {
+            model_path: args.model_path.clone().unwrap(),
+            load_seconds: load_time.as_secs_f64(),
+            prompt_tokens: runs.first().map_or(0, |run| run.prompt_tokens),
+            gen_tokens: *gen_tokens,
+            repetitions: *repetitions,
+            prefill_mode: prefill_mode(&bench_args),
+            time_to_first_token_seconds: Summary::new(
+                &runs
+                    .iter()
+                    .map(|run| run.time_to_first_token.as_secs_f64())
+                    .collect::<Vec<_>>(),
+            ),
+            prefill_tokens_per_second: Summary::new(
+                &runs
+                    .iter()
+                    .map(|run| tokens_per_second(run.prompt_tokens, run.prefill_time))
+                    .collect::<Vec<_>>(),
+            ),
+            decode_tokens_per_second: Summary::new(
+                &runs
+                    .iter()
+                    .map(|run| tokens_per_second(run.decode_tokens, run.decode_time))
+                    .collect::<Vec<_>>(),
+            ),
+        };
+        if *json {
+            println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        } else {
+            println!("model_path: {}", report.model_path);
+            println!("load_time: {:.2} seconds", report.load_seconds);
+            println!(
+                "prompt_tokens: {}, gen_tokens: {}, repetitions: {}",
+                report.prompt_tokens, report.gen_tokens, report.repetitions
+            );
+            println!("prefill: {}", report.prefill_mode);
+            for (name, summary) in [
+                (
+                    "time_to_first_token (s)",
+                    &report.time_to_first_token_seconds,
+                ),
+                ("prefill (tokens/s)", &report.prefill_tokens_per_second),
+                ("decode (tokens/s)", &report.decode_tokens_per_second),
+            ] {
+                println!(
+                    "{name}: mean {:.3}, p50 {:.3}, p95 {:.3}",
+                    summary.mean, summary.p50, summary.p95
+                );
+            }
+        }
+        return;
+    }
+
+    let cli_args = WavvyArgs {
         sample_len: args.sample_len,
         temperature: args.temperature,
         top_p: args.top_p,
         top_k: args.top_k,
         seed: args.seed,
         split_prompt: args.split_prompt,
+        prefill_chunk_size: args.prefill_chunk_size,
         repeat_penalty: args.repeat_penalty,
         repeat_last_n: args.repeat_last_n,
-    });
+        dry_multiplier: args.dry_multiplier,
+        dry_base: args.dry_base,
+        dry_allowed_length: args.dry_allowed_length,
+        dry_penalty_last_n: args.dry_penalty_last_n,
+        dry_sequence_breakers: args.dry_sequence_breakers,
+        logit_bias: args.logit_bias.into_iter().collect::<HashMap<_, _>>(),
+        banned_strings: args.banned_strings,
+        num_draft_tokens: args.num_draft_tokens,
+        prompt_lookup_num_tokens: args.prompt_lookup_num_tokens,
+        prompt_lookup_max_ngram: args.prompt_lookup_max_ngram,
+        num_beams: args.num_beams,
+        length_penalty: args.length_penalty,
+        early_stopping: args.early_stopping,
+        n: args.n,
+        best_of: args.best_of,
+        max_duration: args.max_duration.map(Duration::from_secs_f64),
+        channel_capacity: 16,
+        context_shift: args.context_shift,
+        sink_tokens: args.sink_tokens,
+        kv_cache_dtype: args.kv_cache_dtype,
+        kv_cache_capacity: args.kv_cache_capacity,
+    };
+    // The flag defaults are the library ones, so only flags given explicitly
+    // change the preset.
+    let mut wavvy_args = preset.unwrap_or_default();
+    macro_rules! override_explicit {
+        ($($field:ident),*) => {
+            $(if explicit(stringify!($field)) {
+                wavvy_args.$field = cli_args.$field.clone();
+            })*
+        };
+    }
+    override_explicit!(
+        sample_len,
+        temperature,
+        top_p,
+        top_k,
+        seed,
+        split_prompt,
+        prefill_chunk_size,
+        repeat_penalty,
+        repeat_last_n,
+        dry_multiplier,
+        dry_base,
+        dry_allowed_length,
+        dry_penalty_last_n,
+        dry_sequence_breakers,
+        logit_bias,
+        banned_strings,
+        num_draft_tokens,
+        prompt_lookup_num_tokens,
+        prompt_lookup_max_ngram,
+        num_beams,
+        length_penalty,
+        early_stopping,
+        n,
+        best_of,
+        max_duration,
+        context_shift,
+        sink_tokens,
+        kv_cache_dtype,
+        kv_cache_capacity
+    );
+    wavvy_args.validate().unwrap();
+
+    if let Some(Command::Chat {
+        system,
+        history_file,
+    }) = &args.command
+    {
+        let model_name = if args.model_name == "r1" {
+            Model::R1
+        } else {
+            Model::W
+        };
+        let session = ChatSession {
+            model_name,
+            model,
+            tokenizer,
+            device,
+            args: wavvy_args,
+            truncation: args
+                .truncation
+                .clone()
+                .unwrap_or(TruncationStrategy::DropOldest),
+        };
+        session.run(system.clone(), history_file.as_deref()).await;
+        return;
+    }
+
+    let question = args.prompt.unwrap();
 
     let model_name = if args.model_name == "r1" {
         Model::R1
@@ -103,16 +885,104 @@ async fn main() {
         Model::W
     };
 
-    let wavvy = WavvyChat::new(model_name, model, tokenizer, &device, wavvy_args);
+    let messages = vec![Message::new(Role::User, question.clone())];
+
+    let mut message_template = ChatTemplate::new(model_name, messages);
+
+    if let Some(truncation) = &args.truncation {
+        let max_prompt_tokens = model.context_length().saturating_sub(wavvy_args.sample_len);
+        message_template
+            .truncate(&tokenizer, max_prompt_tokens, truncation)
+            .unwrap();
+    }
+
+    let model_name = if args.model_name == "r1" {
+        Model::R1
+    } else {
+        Model::W
+    };
+
+    let mut wavvy = WavvyChat::new(model_name, model, tokenizer, &device, Some(wavvy_args));
+    if wavvy.args.prefill_chunk_size.is_some() {
+        wavvy = wavvy.with_prefill_progress(|progress| {
+            eprintln!("prefill: {}/{} tokens", progress.processed, progress.total);
+        });
+    }
+    if let Some(draft_model_path) = &args.draft_model_path {
+        let draft_builder = ModelBuilder::new(
+            draft_model_path,
+            model_builder.tokenizer_path.as_str(),
+            &device,
+        );
+        wavvy = wavvy.with_draft_model(draft_builder.load_model().unwrap());
+        println!("Draft model loaded");
+    }
+
+    if wavvy.args.num_beams > 1 {
+        println!("Question: {question}");
+        let time_process = Instant::now();
+        let hypotheses = wavvy.beam_search(message_template.format()).unwrap();
+        for (rank, hypothesis) in hypotheses.iter().enumerate() {
+            println!(
+                "\n[{rank}] score: {:.4}, log_prob: {:.4}, tokens: {}",
+                hypothesis.score,
+                hypothesis.log_prob,
+                hypothesis.tokens.len()
+            );
+            println!("{}", hypothesis.content);
+        }
+        println!(
+            "\ntotal_time: {:.2} seconds",
+            time_process.elapsed().as_secs_f64()
+        );
+        return;
+    }
+    if wavvy.args.n > 1 || wavvy.args.best_of.is_some_and(|best_of| best_of > 1) {
+        println!("Question: {question}");
+        let time_process = Instant::now();
+        let mut response = wavvy.stream_invoke(message_template.format()).unwrap();
+        let mut choices: Vec<ChatResponse> = vec![];
+        while let Some(item) = response.next().await {
+            match item {
+                Ok(response) => {
+                    if choices.len() <= response.index {
+                        choices.resize_with(response.index + 1, ChatResponse::default);
+                    }
+                    choices[response.index].merge(response);
+                }
+                Err(e) => {
+                    println!("Error: {}", e);
+                }
+            }
+        }
+        for (index, choice) in choices.iter().enumerate() {
+            println!(
+                "\n[{index}] log_prob: {:.4}, mean_log_prob: {:.4}, tokens: {}",
+                choice.log_prob,
+                choice.mean_log_prob(),
+                choice.completion_tokens
+            );
+            println!("{}", choice.content);
+        }
+        println!(
+            "\ntotal_time: {:.2} seconds",
+            time_process.elapsed().as_secs_f64()
+        );
+        return;
+    }
+    let time_process = Instant::now();
     let mut response = wavvy.stream_invoke(message_template.format()).unwrap();
 
+    let mut latency = None;
     let mut prompt_tokens = 0;
     let mut completion_tokens = 0;
     let mut total_tokens = 0;
+    let mut speculative = None;
+    let mut finish_reason = None;
+    let mut kv_cache = None;
 
     println!("Question: {question}");
     print!("Answer: ");
-    let time_process = std::time::Instant::now();
     while let Some(item) = response.next().await {
         match item {
             Ok(response) => {
@@ -120,6 +990,10 @@ async fn main() {
                 prompt_tokens = response.prompt_tokens;
                 completion_tokens = response.completion_tokens;
                 total_tokens = response.total_tokens;
+                speculative = response.speculative;
+                finish_reason = response.finish_reason;
+                kv_cache = Some(response.kv_cache);
+                latency = response.latency.or(latency);
             }
             Err(e) => {
                 println!("Error: {}", e);
@@ -133,8 +1007,50 @@ async fn main() {
     println!("prompt_tokens: {} tokens", prompt_tokens);
     println!("completion_tokens: {} tokens", completion_tokens);
     println!("total_tokens: {} tokens", total_tokens);
-    println!(
-        "total tokens generated: {:.2} tokens/s",
-        total_tokens as f64 / dt.as_secs_f64()
-    );
+    if let Some(kv_cache) = kv_cache {
+        println!(
+            "kv_cache: {}/{} tokens, {:.1} MiB",
+            kv_cache.tokens,
+            kv_cache.capacity,
+            kv_cache.bytes as f64 / (1024. * 1024.)
+        );
+    }
+    if let Some(finish_reason) = finish_reason {
+        println!("finish_reason: {:?}", finish_reason);
+    }
+    if let Some(latency) = latency {
+        println!(
+            "queue: {:.3} seconds, tokenization: {:.3} seconds",
+            latency.queue.as_secs_f64(),
+            latency.tokenization.as_secs_f64()
+        );
+        println!(
+            "time_to_first_token: {:.2} seconds",
+            latency.time_to_first_token.as_secs_f64()
+        );
+        println!(
+            "prefill: {:.2} seconds, {:.2} tokens/s",
+            latency.prefill.as_secs_f64(),
+            prompt_tokens as f64 / latency.prefill.as_secs_f64()
+        );
+        println!(
+            "decode: {:.2} seconds, {:.2} tokens/s",
+            latency.decode.as_secs_f64(),
+            completion_tokens.saturating_sub(1) as f64 / latency.decode.as_secs_f64()
+        );
+        println!(
+            "inter_token_latency: p50 {:.1} ms, p95 {:.1} ms, p99 {:.1} ms",
+            latency.inter_token_p50.as_secs_f64() * 1000.,
+            latency.inter_token_p95.as_secs_f64() * 1000.,
+            latency.inter_token_p99.as_secs_f64() * 1000.
+        );
+    }
+    if let Some(speculative) = speculative {
+        println!(
+            "draft acceptance: {}/{} tokens ({:.1}%)",
+            speculative.accepted_tokens,
+            speculative.draft_tokens,
+            speculative.acceptance_rate() * 100.
+        );
+    }
 }
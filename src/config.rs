@@ -0,0 +1,100 @@
+//! Named sampling presets and model definitions, loaded from a TOML or JSON
+//! file:
+//!
+//! ```toml
+//! [presets.precise]
+//! temperature = 0.2
+//! top_p = 0.9
+//!
+//! [models.qwen]
+//! model_path = "./model/Qwen2.5-3B-Instruct/qwen2.5-3b-instruct-q4_0.gguf"
+//! tokenizer_path = "./model/Qwen2.5-3B-Instruct/tokenizer.json"
+//! preset = "precise"
+//! ```
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use candle_core::Device;
+use serde::{Deserialize, Serialize};
+
+use crate::llm::model_builder::ModelBuilder;
+use crate::llm::wavvy_chat_stream::{WavvyArgs, WavvyError};
+use crate::prompt_template::chat_template::Model;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WavvyConfig {
+    pub presets: HashMap<String, WavvyArgs>,
+    pub models: HashMap<String, ModelDefinition>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ModelDefinition {
+    pub model_path: String,
+    pub tokenizer_path: String,
+    #[serde(default)]
+    pub template: Model,
+    #[serde(default)]
+    pub draft_model_path: Option<String>,
+    /// Preset used when the request names none.
+    #[serde(default)]
+    pub preset: Option<String>,
+}
+
+impl ModelDefinition {
+    pub fn builder(&self, device: &Device) -> ModelBuilder {
+        ModelBuilder::new(&self.model_path, &self.tokenizer_path, device)
+    }
+}
+
+impl WavvyConfig {
+    /// Parses the file as JSON when it ends in `.json`, as TOML otherwise.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, WavvyError> {
+        let path = path.as_ref();
+        let invalid_config = |source| WavvyError::InvalidConfig {
+            context: path.display().to_string(),
+            source,
+        };
+        let content = std::fs::read_to_string(path).map_err(|e| invalid_config(e.into()))?;
+        let config = if path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            Self::from_json_str(&content)
+        } else {
+            Self::from_toml_str(&content)
+        };
+        config.map_err(|e| match e {
+            WavvyError::InvalidConfig { source, .. } => invalid_config(source),
+            e => e,
+        })
+    }
+
+    pub fn from_toml_str(content: &str) -> Result<Self, WavvyError> {
+        toml::from_str(content).map_err(|e| WavvyError::InvalidConfig {
+            context: String::from("TOML"),
+            source: e.into(),
+        })
+    }
+
+    pub fn from_json_str(content: &str) -> Result<Self, WavvyError> {
+        serde_json::from_str(content).map_err(|e| WavvyError::InvalidConfig {
+            context: String::from("JSON"),
+            source: e.into(),
+        })
+    }
+
+    pub fn preset(&self, name: &str) -> Result<WavvyArgs, WavvyError> {
+        self.presets
+            .get(name)
+            .cloned()
+            .ok_or_else(|| WavvyError::ConfigError(format!("no preset named '{name}'")))
+    }
+
+    pub fn model(&self, name: &str) -> Result<&ModelDefinition, WavvyError> {
+        self.models
+            .get(name)
+            .ok_or_else(|| WavvyError::ConfigError(format!("no model named '{name}'")))
+    }
+}
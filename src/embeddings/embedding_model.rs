@@ -0,0 +1,96 @@
+use candle_core::{DType, Device, Tensor, D};
+use candle_transformers::models::bert::BertModel;
+use tokenizers::{Encoding, Tokenizer};
+
+use crate::llm::wavvy_chat_stream::WavvyError;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Pooling {
+    /// Average of the token vectors, padding excluded. GTE and all-MiniLM.
+    #[default]
+    Mean,
+    /// Vector of the first token. BGE v1.5.
+    Cls,
+}
+
+/// BERT-family encoder turning sentences into fixed-size vectors.
+pub struct EmbeddingModel {
+    model: BertModel,
+    tokenizer: Tokenizer,
+    device: Device,
+    pub pooling: Pooling,
+    pub normalize: bool,
+    pub batch_size: usize,
+}
+
+impl EmbeddingModel {
+    pub fn new(model: BertModel, tokenizer: Tokenizer, device: &Device, pooling: Pooling) -> Self {
+        Self {
+            model,
+            tokenizer,
+            device: device.clone(),
+            pooling,
+            normalize: true,
+            batch_size: 32,
+        }
+    }
+
+    pub fn embed_one(&self, text: &str) -> Result<Vec<f32>, WavvyError> {
+        let mut embeddings = self.embed(&[text])?;
+        embeddings
+            .pop()
+            .ok_or_else(|| WavvyError::PromptError(String::from("no embedding was computed")))
+    }
+
+    /// One vector per text, in the same order.
+    pub fn embed(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>, WavvyError> {
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for batch in texts.chunks(self.batch_size.max(1)) {
+            let encodings = self
+                .tokenizer
+                .encode_batch(batch.to_vec(), true)
+                .map_err(WavvyError::TokenizerError)?;
+            let embedding = self
+                .embed_batch(&encodings)
+                .map_err(WavvyError::ForwardError)?;
+            embeddings.extend(embedding);
+        }
+        Ok(embeddings)
+    }
+
+    fn embed_batch(&self, encodings: &[Encoding]) -> candle_core::Result<Vec<Vec<f32>>> {
+        let tensor = |ids: Vec<&[u32]>| -> candle_core::Result<Tensor> {
+            let ids = ids
+                .into_iter()
+                .map(|ids| Tensor::new(ids, &self.device))
+                .collect::<candle_core::Result<Vec<_>>>()?;
+            Tensor::stack(&ids, 0)
+        };
+        let input_ids = tensor(encodings.iter().map(|e| e.get_ids()).collect())?;
+        let token_type_ids = tensor(encodings.iter().map(|e| e.get_type_ids()).collect())?;
+        let attention_mask = tensor(encodings.iter().map(|e| e.get_attention_mask()).collect())?;
+
+        let hidden = self
+            .model
+            .forward(&input_ids, &token_type_ids, Some(&attention_mask))?;
+        let pooled = match self.pooling {
+            Pooling::Cls => hidden.narrow(1, 0, 1)?.squeeze(1)?,
+            Pooling::Mean => {
+                let mask = attention_mask.to_dtype(DType::F32)?.unsqueeze(D::Minus1)?;
+                let sum = hidden.broadcast_mul(&mask)?.sum(1)?;
+                sum.broadcast_div(&mask.sum(1)?.clamp(1e-9, f64::MAX)?)?
+            }
+        };
+        let pooled = if self.normalize {
+            let norm = pooled
+                .sqr()?
+                .sum_keepdim(D::Minus1)?
+                .sqrt()?
+                .clamp(1e-12, f64::MAX)?;
+            pooled.broadcast_div(&norm)?
+        } else {
+            pooled
+        };
+        pooled.to_dtype(DType::F32)?.to_vec2::<f32>()
+    }
+}
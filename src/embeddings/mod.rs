@@ -0,0 +1,3 @@
+pub mod embedding_model;
+pub mod model_builder;
+pub mod reranker;
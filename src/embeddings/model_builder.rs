@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use candle_core::{quantized::gguf_file, DType, Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::bert::{BertModel, Config, HiddenAct};
+use candle_transformers::models::xlm_roberta::{self, XLMRobertaForSequenceClassification};
+use tokenizers::{PaddingParams, Tokenizer, TruncationParams, TruncationStrategy};
+
+use super::embedding_model::{EmbeddingModel, Pooling};
+use super::reranker::Reranker;
+use crate::llm::wavvy_chat_stream::WavvyError;
+
+/// Loads a BERT-family encoder from safetensors, next to its `config.json`, or
+/// from a GGUF file.
+#[derive(Debug)]
+pub struct EmbeddingModelBuilder {
+    pub model_path: String,
+    pub tokenizer_path: String,
+    pub config_path: Option<String>,
+    pub pooling: Option<Pooling>,
+    pub device: Device,
+}
+
+impl EmbeddingModelBuilder {
+    pub fn new(model_path: &str, tokenizer_path: &str, device: &Device) -> Self {
+        Self {
+            model_path: model_path.to_string(),
+            tokenizer_path: tokenizer_path.to_string(),
+            config_path: None,
+            pooling: None,
+            device: device.clone(),
+        }
+    }
+
+    pub fn with_config_path(mut self, config_path: &str) -> Self {
+        self.config_path = Some(config_path.to_string());
+        self
+    }
+
+    /// Overrides the pooling, GGUF files otherwise tell which one they were
+    /// trained with.
+    pub fn with_pooling(mut self, pooling: Pooling) -> Self {
+        self.pooling = Some(pooling);
+        self
+    }
+
+    fn model_load_error(&self, source: candle_core::Error) -> WavvyError {
+        WavvyError::ModelLoadError {
+            path: self.model_path.clone(),
+            source,
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(path = %self.model_path))]
+    pub fn load(&self) -> Result<EmbeddingModel, WavvyError> {
+        let is_gguf = Path::new(&self.model_path)
+            .extension()
+            .is_some_and(|extension| extension == "gguf");
+        let (model, config, gguf_pooling) = if is_gguf {
+            self.load_gguf()?
+        } else {
+            let (model, config) = self.load_safetensors()?;
+            (model, config, None)
+        };
+        let tokenizer = self.load_tokenizer(config.max_position_embeddings)?;
+        let pooling = self.pooling.or(gguf_pooling).unwrap_or_default();
+        Ok(EmbeddingModel::new(model, tokenizer, &self.device, pooling))
+    }
+
+    fn load_tokenizer(&self, max_len: usize) -> Result<Tokenizer, WavvyError> {
+        let mut tokenizer =
+            Tokenizer::from_file(&self.tokenizer_path).map_err(WavvyError::TokenizerError)?;
+        tokenizer.with_padding(Some(PaddingParams::default()));
+        tokenizer
+            .with_truncation(Some(TruncationParams {
+                max_length: max_len,
+                ..Default::default()
+            }))
+            .map_err(WavvyError::TokenizerError)?;
+        Ok(tokenizer)
+    }
+
+    fn load_safetensors(&self) -> Result<(BertModel, Config), WavvyError> {
+        let config_path = match &self.config_path {
+            Some(config_path) => config_path.into(),
+            None => Path::new(&self.model_path).with_file_name("config.json"),
+        };
+        let config =
+            std::fs::read_to_string(&config_path).map_err(|e| self.model_load_error(e.into()))?;
+        let config: Config =
+            serde_json::from_str(&config).map_err(|e| WavvyError::InvalidConfig {
+                context: config_path.display().to_string(),
+                source: e.into(),
+            })?;
+
+        // Safety: the weights are only read, the file must not change while mapped.
+        let vb = unsafe {
+            VarBuilder::from_mmaped_safetensors(&[&self.model_path], DType::F32, &self.device)
+        }
+        .map_err(|e| self.model_load_error(e))?;
+        let model = BertModel::load(vb, &config).map_err(|e| self.model_load_error(e))?;
+        Ok((model, config))
+    }
+
+    fn load_gguf(&self) -> Result<(BertModel, Config, Option<Pooling>), WavvyError> {
+        let mut file =
+            std::fs::File::open(&self.model_path).map_err(|e| self.model_load_error(e.into()))?;
+        let content = gguf_file::Content::read(&mut file).map_err(|e| self.model_load_error(e))?;
+
+        let md_get = |key: &str| {
+            content.metadata.get(key).ok_or_else(|| {
+                self.model_load_error(candle_core::Error::msg(format!(
+                    "cannot find {key} in metadata"
+                )))
+            })
+        };
+        let md_usize = |key: &str| -> Result<usize, WavvyError> {
+            md_get(key)?
+                .to_u32()
+                .map(|value| value as usize)
+                .map_err(|e| self.model_load_error(e))
+        };
+
+        let mut tensors = HashMap::new();
+        for name in content.tensor_infos.keys() {
+            let tensor = content
+                .tensor(&mut file, name, &self.device)
+                .and_then(|tensor| tensor.dequantize(&self.device))
+                .map_err(|e| self.model_load_error(e))?;
+            tensors.insert(gguf_tensor_name(name), tensor);
+        }
+
+        let dim = |name: &str, index: usize| -> Result<usize, WavvyError> {
+            tensors
+                .get(name)
+                .map(Tensor::dims)
+                .and_then(|dims| dims.get(index).copied())
+                .ok_or_else(|| {
+                    self.model_load_error(candle_core::Error::msg(format!("cannot find {name}")))
+                })
+        };
+        let config = Config {
+            vocab_size: dim("embeddings.word_embeddings.weight", 0)?,
+            hidden_size: md_usize("bert.embedding_length")?,
+            num_hidden_layers: md_usize("bert.block_count")?,
+            num_attention_heads: md_usize("bert.attention.head_count")?,
+            intermediate_size: md_usize("bert.feed_forward_length")?,
+            hidden_act: HiddenAct::Gelu,
+            max_position_embeddings: md_usize("bert.context_length")?,
+            type_vocab_size: dim("embeddings.token_type_embeddings.weight", 0)?,
+            layer_norm_eps: md_get("bert.attention.layer_norm_epsilon")?
+                .to_f32()
+                .map_err(|e| self.model_load_error(e))? as f64,
+            ..Config::default()
+        };
+        // llama.cpp pooling types: 1 is mean, 2 is CLS.
+        let pooling = match md_usize("bert.pooling_type") {
+            Ok(2) => Some(Pooling::Cls),
+            Ok(1) => Some(Pooling::Mean),
+            _ => None,
+        };
+
+        let vb = VarBuilder::from_tensors(tensors, DType::F32, &self.device);
+        let model = BertModel::load(vb, &config).map_err(|e| self.model_load_error(e))?;
+        Ok((model, config, pooling))
+    }
+}
+
+/// Loads a cross-encoder reranker from safetensors, next to its `config.json`.
+#[derive(Debug)]
+pub struct RerankerBuilder {
+    pub model_path: String,
+    pub tokenizer_path: String,
+    pub config_path: Option<String>,
+    pub max_length: usize,
+    pub device: Device,
+}
+
+impl RerankerBuilder {
+    pub fn new(model_path: &str, tokenizer_path: &str, device: &Device) -> Self {
+        Self {
+            model_path: model_path.to_string(),
+            tokenizer_path: tokenizer_path.to_string(),
+            config_path: None,
+            max_length: 512,
+            device: device.clone(),
+        }
+    }
+
+    pub fn with_config_path(mut self, config_path: &str) -> Self {
+        self.config_path = Some(config_path.to_string());
+        self
+    }
+
+    /// Tokens of a query and document pair, the longer of the two is cut first.
+    pub fn with_max_length(mut self, max_length: usize) -> Self {
+        self.max_length = max_length;
+        self
+    }
+
+    fn model_load_error(&self, source: candle_core::Error) -> WavvyError {
+        WavvyError::ModelLoadError {
+            path: self.model_path.clone(),
+            source,
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(path = %self.model_path))]
+    pub fn load(&self) -> Result<Reranker, WavvyError> {
+        let config_path = match &self.config_path {
+            Some(config_path) => config_path.into(),
+            None => Path::new(&self.model_path).with_file_name("config.json"),
+        };
+        let config =
+            std::fs::read_to_string(&config_path).map_err(|e| self.model_load_error(e.into()))?;
+        let invalid_config = |e: serde_json::Error| WavvyError::InvalidConfig {
+            context: config_path.display().to_string(),
+            source: e.into(),
+        };
+        let config: serde_json::Value = serde_json::from_str(&config).map_err(invalid_config)?;
+        let num_labels = config
+            .get("id2label")
+            .and_then(|labels| labels.as_object())
+            .map_or(1, |labels| labels.len());
+        let config: xlm_roberta::Config = serde_json::from_value(config).map_err(invalid_config)?;
+
+        // Safety: the weights are only read, the file must not change while mapped.
+        let vb = unsafe {
+            VarBuilder::from_mmaped_safetensors(&[&self.model_path], DType::F32, &self.device)
+        }
+        .map_err(|e| self.model_load_error(e))?;
+        let model = XLMRobertaForSequenceClassification::new(num_labels, &config, vb)
+            .map_err(|e| self.model_load_error(e))?;
+
+        let mut tokenizer =
+            Tokenizer::from_file(&self.tokenizer_path).map_err(WavvyError::TokenizerError)?;
+        tokenizer.with_padding(Some(PaddingParams {
+            pad_id: config.pad_token_id,
+            ..Default::default()
+        }));
+        tokenizer
+            .with_truncation(Some(TruncationParams {
+                max_length: self.max_length.min(config.max_position_embeddings),
+                strategy: TruncationStrategy::LongestFirst,
+                ..Default::default()
+            }))
+            .map_err(WavvyError::TokenizerError)?;
+        Ok(Reranker::new(model, tokenizer, &self.device, num_labels))
+    }
+}
+
+/// Maps llama.cpp BERT tensor names to the Hugging Face ones `BertModel` loads.
+fn gguf_tensor_name(name: &str) -> String {
+    let (base, suffix) = name.rsplit_once('.').unwrap_or((name, ""));
+    let base = match base {
+        "token_embd" => String::from("embeddings.word_embeddings"),
+        "token_types" => String::from("embeddings.token_type_embeddings"),
+        "position_embd" => String::from("embeddings.position_embeddings"),
+        "token_embd_norm" => String::from("embeddings.LayerNorm"),
+        _ => match base
+            .strip_prefix("blk.")
+            .and_then(|rest| rest.split_once('.'))
+        {
+            Some((layer, tensor)) => {
+                let tensor = match tensor {
+                    "attn_q" => "attention.self.query",
+                    "attn_k" => "attention.self.key",
+                    "attn_v" => "attention.self.value",
+                    "attn_output" => "attention.output.dense",
+                    "attn_output_norm" => "attention.output.LayerNorm",
+                    "ffn_up" => "intermediate.dense",
+                    "ffn_down" => "output.dense",
+                    "layer_output_norm" => "output.LayerNorm",
+                    tensor => tensor,
+                };
+                format!("encoder.layer.{layer}.{tensor}")
+            }
+            None => base.to_string(),
+        },
+    };
+    format!("{base}.{suffix}")
+}
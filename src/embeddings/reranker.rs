@@ -0,0 +1,82 @@
+use candle_core::{DType, Device, Tensor, D};
+use candle_transformers::models::xlm_roberta::XLMRobertaForSequenceClassification;
+use tokenizers::{Encoding, Tokenizer};
+
+use crate::llm::wavvy_chat_stream::WavvyError;
+
+/// Cross-encoder scoring how relevant each document is to a query, like the
+/// bge-reranker models.
+pub struct Reranker {
+    model: XLMRobertaForSequenceClassification,
+    tokenizer: Tokenizer,
+    device: Device,
+    num_labels: usize,
+    pub batch_size: usize,
+}
+
+impl Reranker {
+    pub fn new(
+        model: XLMRobertaForSequenceClassification,
+        tokenizer: Tokenizer,
+        device: &Device,
+        num_labels: usize,
+    ) -> Self {
+        Self {
+            model,
+            tokenizer,
+            device: device.clone(),
+            num_labels,
+            batch_size: 16,
+        }
+    }
+
+    /// Indices of `documents` with their relevance between 0 and 1, most
+    /// relevant first.
+    pub fn rerank(&self, query: &str, documents: &[&str]) -> Result<Vec<(usize, f32)>, WavvyError> {
+        let mut scores = Vec::with_capacity(documents.len());
+        for batch in documents.chunks(self.batch_size.max(1)) {
+            let pairs = batch
+                .iter()
+                .map(|document| (query, *document))
+                .collect::<Vec<_>>();
+            let encodings = self
+                .tokenizer
+                .encode_batch(pairs, true)
+                .map_err(WavvyError::TokenizerError)?;
+            let batch_scores = self
+                .score_batch(&encodings)
+                .map_err(WavvyError::ForwardError)?;
+            scores.extend(batch_scores);
+        }
+        let mut ranked: Vec<(usize, f32)> = scores.into_iter().enumerate().collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+        Ok(ranked)
+    }
+
+    fn score_batch(&self, encodings: &[Encoding]) -> candle_core::Result<Vec<f32>> {
+        let tensor = |ids: Vec<&[u32]>| -> candle_core::Result<Tensor> {
+            let ids = ids
+                .into_iter()
+                .map(|ids| Tensor::new(ids, &self.device))
+                .collect::<candle_core::Result<Vec<_>>>()?;
+            Tensor::stack(&ids, 0)
+        };
+        let input_ids = tensor(encodings.iter().map(|e| e.get_ids()).collect())?;
+        let token_type_ids = tensor(encodings.iter().map(|e| e.get_type_ids()).collect())?;
+        let attention_mask = tensor(encodings.iter().map(|e| e.get_attention_mask()).collect())?;
+
+        let logits = self
+            .model
+            .forward(&input_ids, &attention_mask, &token_type_ids)?
+            .to_dtype(DType::F32)?;
+        // A single logit is a relevance score, otherwise the last label is "relevant".
+        let scores = if self.num_labels == 1 {
+            candle_nn::ops::sigmoid(&logits)?.squeeze(D::Minus1)?
+        } else {
+            candle_nn::ops::softmax_last_dim(&logits)?
+                .narrow(D::Minus1, self.num_labels - 1, 1)?
+                .squeeze(D::Minus1)?
+        };
+        scores.to_vec1::<f32>()
+    }
+}
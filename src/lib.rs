@@ -1,2 +1,6 @@
+pub mod config;
+pub mod embeddings;
 pub mod llm;
 pub mod prompt_template;
+#[cfg(feature = "otel")]
+pub mod telemetry;
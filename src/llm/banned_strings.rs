@@ -0,0 +1,222 @@
+use std::collections::{HashMap, HashSet};
+
+use tokenizers::Result;
+
+use super::token_output::TokenOutput;
+
+pub enum BannedStringsCheck {
+    Release(String),
+    Rewind(usize),
+}
+
+/// Keeps banned phrases out of the completion. Text that could still grow into a
+/// banned string is held back, and when one does appear the generation is rewound
+/// to the token where it started and that token is banned at that position.
+/// Text is only released in whole tokens so a rewind never regenerates text that
+/// was already streamed.
+pub struct BannedStrings {
+    strings: Vec<String>,
+    banned_tokens: HashSet<u32>,
+    position_bans: HashMap<usize, HashSet<u32>>,
+    released_len: usize,
+    released_tokens: usize,
+}
+
+impl BannedStrings {
+    pub fn new(strings: &[String], tos: &TokenOutput) -> Self {
+        // Strings that are a token on their own (special tokens in particular never
+        // show up in the decoded text) are banned by id everywhere.
+        let banned_tokens = strings.iter().filter_map(|s| tos.get_token(s)).collect();
+        Self {
+            strings: strings.iter().filter(|s| !s.is_empty()).cloned().collect(),
+            banned_tokens,
+            position_bans: HashMap::new(),
+            released_len: 0,
+            released_tokens: 0,
+        }
+    }
+
+    pub fn banned_tokens(&self, position: usize) -> impl Iterator<Item = &u32> {
+        self.banned_tokens
+            .iter()
+            .chain(self.position_bans.get(&position).into_iter().flatten())
+    }
+
+    pub fn ban(&mut self, position: usize, token: u32) {
+        self.position_bans.retain(|p, _| *p <= position);
+        self.position_bans
+            .entry(position)
+            .or_default()
+            .insert(token);
+    }
+
+    pub fn check(&mut self, tos: &TokenOutput) -> Result<BannedStringsCheck> {
+        let text = tos.decode_all()?;
+        let start = floor_char_boundary(&text, self.released_len);
+
+        let matched_at = self
+            .strings
+            .iter()
+            .filter_map(|s| text[start..].find(s.as_str()))
+            .min()
+            .map(|pos| start + pos);
+        if let Some(matched_at) = matched_at {
+            return Ok(BannedStringsCheck::Rewind(self.token_at(tos, matched_at)?));
+        }
+
+        let held = self
+            .strings
+            .iter()
+            .flat_map(|s| s.char_indices().skip(1).map(move |(i, _)| &s[..i]))
+            .filter(|prefix| text.ends_with(prefix))
+            .map(|prefix| prefix.len())
+            .max()
+            .unwrap_or(0);
+        // An incomplete UTF-8 sequence decodes to a replacement character, wait for
+        // the rest of it before releasing.
+        let held = held.max(text.len() - text.trim_end_matches('\u{FFFD}').len());
+        let end = floor_char_boundary(&text, text.len() - held).max(start);
+        // Hold back the rest of a token that is only partly releasable.
+        let released_tokens = self.token_at(tos, end)?;
+        let end = tos.decode(&tos.tokens()[..released_tokens])?.len();
+        let end = floor_char_boundary(&text, end).max(start);
+
+        self.released_len = end;
+        self.released_tokens = released_tokens;
+        Ok(BannedStringsCheck::Release(text[start..end].to_string()))
+    }
+
+    pub fn flush(&mut self, tos: &TokenOutput) -> Result<String> {
+        let text = tos.decode_all()?;
+        let start = floor_char_boundary(&text, self.released_len);
+        self.released_len = text.len();
+        self.released_tokens = tos.tokens().len();
+        Ok(text[start..].to_string())
+    }
+
+    /// Number of leading tokens whose text fits within `text_pos`, never less than
+    /// the tokens already released.
+    fn token_at(&self, tos: &TokenOutput, text_pos: usize) -> Result<usize> {
+        let tokens = tos.tokens();
+        let released_tokens = self.released_tokens.min(tokens.len());
+        for index in (released_tokens + 1..=tokens.len()).rev() {
+            if tos.decode(&tokens[..index])?.len() <= text_pos {
+                return Ok(index);
+            }
+        }
+        Ok(released_tokens)
+    }
+}
+
+fn floor_char_boundary(text: &str, index: usize) -> usize {
+    let mut index = index.min(text.len());
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokenizers::Tokenizer;
+
+    fn token_output(text: &str) -> TokenOutput {
+        let tokenizer = Tokenizer::from_file(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/tokenizer.json"
+        ))
+        .unwrap();
+        let tokens = tokenizer.encode(text, false).unwrap().get_ids().to_vec();
+        let mut tos = TokenOutput::new(tokenizer);
+        tokens.into_iter().for_each(|token| tos.push_token(token));
+        tos
+    }
+
+    fn banned_strings(strings: &[&str], tos: &TokenOutput) -> BannedStrings {
+        let strings: Vec<String> = strings.iter().map(|s| s.to_string()).collect();
+        BannedStrings::new(&strings, tos)
+    }
+
+    fn release(check: BannedStringsCheck) -> String {
+        match check {
+            BannedStringsCheck::Release(text) => text,
+            BannedStringsCheck::Rewind(position) => panic!("unexpected rewind to {position}"),
+        }
+    }
+
+    #[test]
+    fn token_at_finds_the_token_containing_a_position() -> Result<()> {
+        // "hello", " w", "orld"
+        let tos = token_output("hello world");
+        assert_eq!(tos.tokens().len(), 3);
+        let banned = banned_strings(&["x"], &tos);
+        assert_eq!(banned.token_at(&tos, 0)?, 0);
+        assert_eq!(banned.token_at(&tos, 5)?, 1);
+        assert_eq!(banned.token_at(&tos, 6)?, 1);
+        assert_eq!(banned.token_at(&tos, 7)?, 2);
+        assert_eq!(banned.token_at(&tos, 11)?, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn check_releases_text_without_banned_prefixes() -> Result<()> {
+        let tos = token_output("hello world");
+        let mut banned = banned_strings(&["xyz"], &tos);
+        assert_eq!(release(banned.check(&tos)?), "hello world");
+        assert_eq!(release(banned.check(&tos)?), "");
+        Ok(())
+    }
+
+    #[test]
+    fn check_holds_back_whole_tokens() -> Result<()> {
+        // The tail "w" could start "wor", and " w" is a single token.
+        let tos = token_output("hello w");
+        let mut banned = banned_strings(&["wor"], &tos);
+        assert_eq!(release(banned.check(&tos)?), "hello");
+        assert_eq!(banned.flush(&tos)?, " w");
+        Ok(())
+    }
+
+    #[test]
+    fn check_never_rewinds_into_released_text() -> Result<()> {
+        let mut tos = token_output("hello w");
+        let mut banned = banned_strings(&["wor"], &tos);
+        assert_eq!(release(banned.check(&tos)?), "hello");
+
+        tos.push_token(tos.get_token("orld").unwrap());
+        match banned.check(&tos)? {
+            BannedStringsCheck::Rewind(position) => assert_eq!(position, 1),
+            BannedStringsCheck::Release(text) => panic!("unexpected release of {text:?}"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn banned_token_strings_are_banned_everywhere() {
+        let tos = token_output("");
+        let banned = banned_strings(&["<|im_end|>", "world"], &tos);
+        let im_end = tos.get_token("<|im_end|>").unwrap();
+        let world = tos.get_token("world").unwrap();
+        let mut tokens: Vec<u32> = banned.banned_tokens(3).copied().collect();
+        tokens.sort();
+        assert_eq!(tokens, vec![world, im_end]);
+    }
+
+    #[test]
+    fn flush_releases_the_held_back_text() -> Result<()> {
+        let tos = token_output("hello w");
+        let mut banned = banned_strings(&["w"], &tos);
+        match banned.check(&tos)? {
+            BannedStringsCheck::Rewind(position) => assert_eq!(position, 1),
+            BannedStringsCheck::Release(text) => panic!("unexpected release of {text:?}"),
+        }
+
+        let tos = token_output("hello wo");
+        let mut banned = banned_strings(&["wor"], &tos);
+        assert_eq!(release(banned.check(&tos)?), "hello");
+        assert_eq!(banned.flush(&tos)?, " wo");
+        assert_eq!(banned.flush(&tos)?, "");
+        Ok(())
+    }
+}
@@ -0,0 +1,263 @@
+use candle_core::{Device, Tensor, D};
+use tokenizers::Tokenizer;
+
+use crate::prompt_template::chat_template::Model;
+
+use super::kv_cache::KvCacheConfig;
+use super::logit_processor::{LogitProcessor, SamplerChain, TokenHistory};
+use super::quantized_qwen2::ModelWeights as Qwen2;
+use super::wavvy_chat_stream::{WavvyArgs, WavvyError};
+
+#[derive(Clone, Debug)]
+pub struct BeamHypothesis {
+    pub content: String,
+    pub tokens: Vec<u32>,
+    pub log_prob: f32,
+    pub score: f32,
+}
+
+struct Beam {
+    model: Qwen2,
+    tokens: Vec<u32>,
+    log_prob: f32,
+    logits: Tensor,
+}
+
+/// Deterministic decoding that keeps the `num_beams` most likely continuations
+/// at every step, each with its own fork of the KV cache.
+pub struct BeamSearch {
+    model: Model,
+    base_model: Qwen2,
+    device: Device,
+    tokenizer: Tokenizer,
+    sampler_chain: SamplerChain,
+    pub args: WavvyArgs,
+}
+
+impl BeamSearch {
+    pub fn new(
+        model: Model,
+        base_model: Qwen2,
+        tokenizer: Tokenizer,
+        device: &Device,
+        args: Option<WavvyArgs>,
+    ) -> Self {
+        Self {
+            model,
+            base_model,
+            device: device.clone(),
+            tokenizer,
+            sampler_chain: SamplerChain::new(),
+            args: args.unwrap_or_default(),
+        }
+    }
+
+    pub fn with_logit_processor(mut self, processor: impl LogitProcessor + 'static) -> Self {
+        self.sampler_chain.push(processor);
+        self
+    }
+
+    pub fn with_sampler_chain(mut self, sampler_chain: SamplerChain) -> Self {
+        self.sampler_chain = sampler_chain;
+        self
+    }
+
+    fn score(&self, log_prob: f32, len: usize) -> f32 {
+        log_prob / (len.max(1) as f32).powf(self.args.length_penalty)
+    }
+
+    fn hypothesis(&self, tokens: Vec<u32>, log_prob: f32) -> Result<BeamHypothesis, WavvyError> {
+        let content = self
+            .tokenizer
+            .decode(&tokens, true)
+            .map_err(WavvyError::TokenizerError)?;
+        Ok(BeamHypothesis {
+            content,
+            score: self.score(log_prob, tokens.len()),
+            tokens,
+            log_prob,
+        })
+    }
+
+    fn is_done(&self, finished: &[BeamHypothesis], best_running: Option<f32>, len: usize) -> bool {
+        if finished.len() < self.args.num_beams.max(1) {
+            return false;
+        }
+        if self.args.early_stopping {
+            return true;
+        }
+        let worst_finished = finished
+            .iter()
+            .map(|hypothesis| hypothesis.score)
+            .fold(f32::INFINITY, f32::min);
+        match best_running {
+            None => true,
+            Some(log_prob) => worst_finished >= self.score(log_prob, len),
+        }
+    }
+
+    /// Returns the best hypotheses, highest score first.
+    pub fn invoke(mut self, prompt_str: String) -> Result<Vec<BeamHypothesis>, WavvyError> {
+        self.args.validate()?;
+        let eos_token = *self
+            .tokenizer
+            .get_vocab(true)
+            .get(self.model.eos_token())
+            .ok_or_else(|| {
+                WavvyError::ConfigError(format!(
+                    "tokenizer has no {} token",
+                    self.model.eos_token()
+                ))
+            })?;
+        let prompt = self
+            .tokenizer
+            .encode(prompt_str, true)
+            .map_err(WavvyError::TokenizerError)?
+            .get_ids()
+            .to_vec();
+        let max_prompt_tokens = self
+            .base_model
+            .context_length()
+            .saturating_sub(self.args.sample_len);
+        if prompt.len() > max_prompt_tokens {
+            return Err(WavvyError::ContextOverflow {
+                prompt_tokens: prompt.len(),
+                max_prompt_tokens,
+            });
+        }
+
+        let custom_processors = std::mem::take(&mut self.sampler_chain);
+        self.sampler_chain = SamplerChain::from_args(&self.args, &self.tokenizer)?;
+        self.sampler_chain.append(custom_processors);
+
+        self.base_model
+            .set_kv_cache_config(KvCacheConfig {
+                dtype: self.args.kv_cache_dtype,
+                capacity: self.args.kv_cache_capacity,
+            })
+            .map_err(|e| WavvyError::InvalidConfig {
+                context: String::from("KV cache"),
+                source: e.into(),
+            })?;
+        let num_beams = self.args.num_beams.max(1);
+        let input = Tensor::new(prompt.as_slice(), &self.device)
+            .map_err(WavvyError::ForwardError)?
+            .unsqueeze(0)
+            .map_err(WavvyError::ForwardError)?;
+        let logits = self
+            .base_model
+            .forward(&input, 0)
+            .map_err(WavvyError::ForwardError)?
+            .squeeze(0)
+            .map_err(WavvyError::ForwardError)?;
+
+        let mut beams = vec![Beam {
+            model: self.base_model.clone(),
+            tokens: vec![],
+            log_prob: 0.,
+            logits,
+        }];
+        self.base_model.clear_kv_cache();
+        let mut finished: Vec<BeamHypothesis> = vec![];
+
+        for step in 0..self.args.sample_len {
+            // (beam, token, cumulative log-probability)
+            let mut candidates: Vec<(usize, u32, f32)> = vec![];
+            for (beam_index, beam) in beams.iter().enumerate() {
+                let history = TokenHistory {
+                    prompt: &prompt,
+                    completion: &beam.tokens,
+                };
+                let logits = self
+                    .sampler_chain
+                    .process(beam.logits.clone(), &history)
+                    .and_then(|logits| candle_nn::ops::log_softmax(&logits, D::Minus1))
+                    .and_then(|log_probs| log_probs.to_vec1::<f32>())
+                    .map_err(WavvyError::SamplingError)?;
+
+                let mut indices = (0..logits.len()).collect::<Vec<_>>();
+                let top = (2 * num_beams).min(indices.len());
+                if top < indices.len() {
+                    indices.select_nth_unstable_by(top, |&i, &j| logits[j].total_cmp(&logits[i]));
+                }
+                candidates.extend(
+                    indices[..top]
+                        .iter()
+                        .map(|&token| (beam_index, token as u32, beam.log_prob + logits[token])),
+                );
+            }
+            candidates.sort_by(|a, b| b.2.total_cmp(&a.2));
+
+            let mut next = vec![];
+            for (rank, (beam_index, token, log_prob)) in candidates.into_iter().enumerate() {
+                if token == eos_token {
+                    if rank < num_beams {
+                        finished.push(self.hypothesis(beams[beam_index].tokens.clone(), log_prob)?);
+                    }
+                    continue;
+                }
+                next.push((beam_index, token, log_prob));
+                if next.len() == num_beams {
+                    break;
+                }
+            }
+
+            let best_running = next.first().map(|(_, _, log_prob)| *log_prob);
+            if self.is_done(&finished, best_running, step + 1) {
+                break;
+            }
+
+            let mut children = vec![0; beams.len()];
+            for &(beam_index, _, _) in &next {
+                children[beam_index] += 1;
+            }
+            let mut parents = beams.into_iter().map(Some).collect::<Vec<_>>();
+            let mut next_beams = Vec::with_capacity(next.len());
+            for (beam_index, token, log_prob) in next {
+                // The last child takes over its parent, so the KV cache is only
+                // copied for its siblings.
+                children[beam_index] -= 1;
+                let (mut model, mut tokens) = match children[beam_index] {
+                    0 => parents[beam_index]
+                        .take()
+                        .map(|parent| (parent.model, parent.tokens)),
+                    _ => parents[beam_index]
+                        .as_ref()
+                        .map(|parent| (parent.model.clone(), parent.tokens.clone())),
+                }
+                .expect("parent beams outlive their children");
+                tokens.push(token);
+                if step + 1 == self.args.sample_len {
+                    finished.push(self.hypothesis(tokens, log_prob)?);
+                    continue;
+                }
+
+                let input = Tensor::new(&[token], &self.device)
+                    .map_err(WavvyError::ForwardError)?
+                    .unsqueeze(0)
+                    .map_err(WavvyError::ForwardError)?;
+                let logits = model
+                    .forward(&input, prompt.len() + tokens.len() - 1)
+                    .map_err(WavvyError::ForwardError)?
+                    .squeeze(0)
+                    .map_err(WavvyError::ForwardError)?;
+                next_beams.push(Beam {
+                    model,
+                    tokens,
+                    log_prob,
+                    logits,
+                });
+            }
+            beams = next_beams;
+        }
+
+        if finished.len() < num_beams {
+            for beam in beams {
+                finished.push(self.hypothesis(beam.tokens, beam.log_prob)?);
+            }
+        }
+        finished.sort_by(|a, b| b.score.total_cmp(&a.score));
+        finished.truncate(num_beams);
+        Ok(finished)
+    }
+}
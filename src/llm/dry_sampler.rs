@@ -0,0 +1,189 @@
+use std::collections::{HashMap, HashSet};
+
+use candle_core::{Result, Tensor};
+use tokenizers::Tokenizer;
+
+use super::logit_processor::{LogitProcessor, TokenHistory};
+use super::wavvy_chat_stream::{WavvyArgs, WavvyError};
+
+/// DRY ("Don't Repeat Yourself") penalty: lowers the logit of every token that
+/// would extend an n-gram already present in the context.
+#[derive(Clone)]
+pub struct DrySampler {
+    multiplier: f32,
+    base: f32,
+    allowed_length: usize,
+    penalty_last_n: usize,
+    sequence_breakers: HashSet<u32>,
+}
+
+impl DrySampler {
+    pub fn new(args: &WavvyArgs, tokenizer: &Tokenizer) -> std::result::Result<Self, WavvyError> {
+        let mut sequence_breakers = HashSet::new();
+        for breaker in &args.dry_sequence_breakers {
+            // Encode behind a dummy character so the breaker is tokenized the way it
+            // appears mid-text instead of at the start of a sequence.
+            let encoding = tokenizer
+                .encode(format!("a{breaker}"), false)
+                .map_err(WavvyError::TokenizerError)?;
+            if let Some(token) = encoding.get_ids().last() {
+                sequence_breakers.insert(*token);
+            }
+        }
+        Ok(Self {
+            multiplier: args.dry_multiplier,
+            base: args.dry_base,
+            allowed_length: args.dry_allowed_length,
+            penalty_last_n: args.dry_penalty_last_n,
+            sequence_breakers,
+        })
+    }
+
+    /// For every token that would extend a repeat of the end of `context`, the
+    /// length of the longest such repeat.
+    fn match_lengths(&self, context: &[u32]) -> HashMap<u32, usize> {
+        let start_at = if self.penalty_last_n == 0 {
+            0
+        } else {
+            context.len().saturating_sub(self.penalty_last_n)
+        };
+        let context = &context[start_at..];
+
+        let mut match_lengths: HashMap<u32, usize> = HashMap::new();
+        let last = match context.last() {
+            Some(last) if !self.sequence_breakers.contains(last) => *last,
+            _ => return match_lengths,
+        };
+
+        for i in 0..context.len() - 1 {
+            if context[i] != last {
+                continue;
+            }
+            let next_token = context[i + 1];
+            if self.sequence_breakers.contains(&next_token) {
+                continue;
+            }
+            let mut match_length = 1;
+            while match_length <= i {
+                let token = context[i - match_length];
+                if self.sequence_breakers.contains(&token)
+                    || token != context[context.len() - 1 - match_length]
+                {
+                    break;
+                }
+                match_length += 1;
+            }
+            let entry = match_lengths.entry(next_token).or_default();
+            *entry = (*entry).max(match_length);
+        }
+        match_lengths
+    }
+
+    fn penalty(&self, match_length: usize) -> Option<f32> {
+        if match_length < self.allowed_length {
+            return None;
+        }
+        let exponent = (match_length - self.allowed_length) as f32;
+        Some(self.multiplier * self.base.powf(exponent))
+    }
+}
+
+impl LogitProcessor for DrySampler {
+    fn process(&mut self, logits: Tensor, history: &TokenHistory) -> Result<Tensor> {
+        let match_lengths = self.match_lengths(&history.all());
+        if match_lengths.is_empty() {
+            return Ok(logits);
+        }
+
+        let device = logits.device();
+        let mut logits = logits.to_dtype(candle_core::DType::F32)?.to_vec1::<f32>()?;
+        for (token, match_length) in match_lengths {
+            let Some(penalty) = self.penalty(match_length) else {
+                continue;
+            };
+            if let Some(logit) = logits.get_mut(token as usize) {
+                *logit -= penalty;
+            }
+        }
+        let logits_len = logits.len();
+        Tensor::from_vec(logits, logits_len, device)
+    }
+
+    fn boxed_clone(&self) -> Box<dyn LogitProcessor> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+
+    fn sampler(penalty_last_n: usize, sequence_breakers: &[u32]) -> DrySampler {
+        DrySampler {
+            multiplier: 2.,
+            base: 1.75,
+            allowed_length: 2,
+            penalty_last_n,
+            sequence_breakers: sequence_breakers.iter().copied().collect(),
+        }
+    }
+
+    #[test]
+    fn match_length_counts_the_repeated_suffix() {
+        let sampler = sampler(0, &[]);
+        let match_lengths = sampler.match_lengths(&[1, 2, 3, 4, 1, 2, 3]);
+        assert_eq!(match_lengths, HashMap::from([(4, 3)]));
+    }
+
+    #[test]
+    fn match_length_keeps_the_longest_repeat_per_token() {
+        let sampler = sampler(0, &[]);
+        // `7` followed the 1-token repeat `3`, `4` the 2-token repeat `2 3`.
+        let match_lengths = sampler.match_lengths(&[3, 7, 2, 3, 4, 2, 3]);
+        assert_eq!(match_lengths, HashMap::from([(7, 1), (4, 2)]));
+    }
+
+    #[test]
+    fn sequence_breakers_end_matches() {
+        let sampler = sampler(0, &[9]);
+        assert_eq!(
+            sampler.match_lengths(&[1, 9, 2, 3, 4, 1, 9, 2, 3]),
+            HashMap::from([(4, 2)])
+        );
+        // Nothing extends a breaker, nor continues into one.
+        assert!(sampler.match_lengths(&[1, 9, 1, 9]).is_empty());
+        assert!(sampler.match_lengths(&[1, 9, 2, 1]).is_empty());
+    }
+
+    #[test]
+    fn penalty_last_n_limits_the_scanned_context() {
+        let sampler = sampler(4, &[]);
+        assert!(sampler.match_lengths(&[1, 2, 3, 4, 5, 6, 1, 2]).is_empty());
+        assert_eq!(
+            sampler.match_lengths(&[1, 2, 3, 1, 2]),
+            HashMap::from([(3, 1)])
+        );
+    }
+
+    #[test]
+    fn penalty_grows_past_the_allowed_length() {
+        let sampler = sampler(0, &[]);
+        assert_eq!(sampler.penalty(1), None);
+        assert_eq!(sampler.penalty(2), Some(2.));
+        assert_eq!(sampler.penalty(4), Some(2. * 1.75 * 1.75));
+    }
+
+    #[test]
+    fn process_lowers_the_repeating_token() -> Result<()> {
+        let mut sampler = sampler(0, &[]);
+        let logits = Tensor::zeros(6, candle_core::DType::F32, &Device::Cpu)?;
+        let history = TokenHistory {
+            prompt: &[1, 2, 3, 4],
+            completion: &[1, 2, 3],
+        };
+        let logits = sampler.process(logits, &history)?.to_vec1::<f32>()?;
+        assert_eq!(logits, vec![0., 0., 0., 0., -3.5, 0.]);
+        Ok(())
+    }
+}
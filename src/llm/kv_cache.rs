@@ -0,0 +1,347 @@
+//! Preallocated KV cache of a single attention layer.
+//!
+//! Keys and values are written in place into buffers sized for the whole context
+//! by default, instead of being concatenated on every decode step. A smaller
+//! `capacity` trades memory for copies, the buffers then grow by doubling. f32
+//! entries are read back as views of the buffers, f16 and q8_0 ones are
+//! converted on every step. Model clones share the buffers until one of them
+//! writes, which then copies only the cached entries first.
+
+use std::fmt;
+use std::sync::Arc;
+
+use candle_core::{DType, Device, Result, Tensor, D};
+use serde::{Deserialize, Serialize};
+
+const Q8_0_BLOCK_SIZE: usize = 32;
+// Room left after the cached entries when a clone copies shared buffers.
+const UNSHARE_HEADROOM: usize = 64;
+
+/// How cached keys and values are stored. `Q8_0` keeps one byte per value plus
+/// an f32 scale per block of 32 values, like GGUF q8_0 weights.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KvCacheDType {
+    #[default]
+    F32,
+    F16,
+    Q8_0,
+}
+
+impl fmt::Display for KvCacheDType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KvCacheDType::F32 => write!(f, "f32"),
+            KvCacheDType::F16 => write!(f, "f16"),
+            KvCacheDType::Q8_0 => write!(f, "q8_0"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KvCacheConfig {
+    pub dtype: KvCacheDType,
+    /// Tokens allocated up front, the whole context when unset.
+    pub capacity: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KvCacheUsage {
+    pub tokens: usize,
+    pub capacity: usize,
+    pub bytes: usize,
+}
+
+impl KvCacheUsage {
+    pub fn merge(self, other: KvCacheUsage) -> Self {
+        Self {
+            tokens: self.tokens.max(other.tokens),
+            capacity: self.capacity.max(other.capacity),
+            bytes: self.bytes + other.bytes,
+        }
+    }
+}
+
+/// Keys or values, shaped `(batch, heads, capacity, head_dim)`.
+#[derive(Debug, Clone)]
+struct CacheBuffer {
+    data: Tensor,
+    scales: Option<Tensor>,
+}
+
+impl CacheBuffer {
+    fn zeros(
+        dtype: KvCacheDType,
+        shape: (usize, usize, usize, usize),
+        device: &Device,
+    ) -> Result<Self> {
+        let (b_sz, n_head, capacity, head_dim) = shape;
+        Ok(match dtype {
+            KvCacheDType::F32 => Self {
+                data: Tensor::zeros(shape, DType::F32, device)?,
+                scales: None,
+            },
+            KvCacheDType::F16 => Self {
+                data: Tensor::zeros(shape, DType::F16, device)?,
+                scales: None,
+            },
+            KvCacheDType::Q8_0 => Self {
+                data: Tensor::zeros(shape, DType::U8, device)?,
+                scales: Some(Tensor::zeros(
+                    (b_sz, n_head, capacity, head_dim / Q8_0_BLOCK_SIZE),
+                    DType::F32,
+                    device,
+                )?),
+            },
+        })
+    }
+
+    fn capacity(&self) -> Result<usize> {
+        self.data.dim(2)
+    }
+
+    fn bytes(&self) -> usize {
+        let bytes = |tensor: &Tensor| tensor.elem_count() * tensor.dtype().size_in_bytes();
+        bytes(&self.data) + self.scales.as_ref().map_or(0, bytes)
+    }
+
+    fn write(&mut self, offset: usize, x: &Tensor) -> Result<()> {
+        match &self.scales {
+            None => {
+                let x = x.to_dtype(self.data.dtype())?.contiguous()?;
+                self.data.slice_set(&x, 2, offset)
+            }
+            Some(scales) => {
+                let (b_sz, n_head, seq_len, head_dim) = x.dims4()?;
+                let blocks = x.reshape((
+                    b_sz,
+                    n_head,
+                    seq_len,
+                    head_dim / Q8_0_BLOCK_SIZE,
+                    Q8_0_BLOCK_SIZE,
+                ))?;
+                let block_scales =
+                    (blocks.abs()?.max_keepdim(D::Minus1)? / 127.)?.maximum(1e-10)?;
+                let quantized = ((blocks.broadcast_div(&block_scales)?.round()? + 128.)?
+                    .clamp(0., 255.)?
+                    .to_dtype(DType::U8)?)
+                .reshape((b_sz, n_head, seq_len, head_dim))?;
+                scales.slice_set(&block_scales.squeeze(D::Minus1)?.contiguous()?, 2, offset)?;
+                self.data.slice_set(&quantized, 2, offset)
+            }
+        }
+    }
+
+    /// Entries `start..start + len` as f32, a view of the buffer for f32.
+    fn read(&self, start: usize, len: usize) -> Result<Tensor> {
+        let data = self.data.narrow(2, start, len)?;
+        match &self.scales {
+            None => data.to_dtype(DType::F32),
+            Some(scales) => {
+                let (b_sz, n_head, _, head_dim) = data.dims4()?;
+                let blocks = (data.to_dtype(DType::F32)? - 128.)?.reshape((
+                    b_sz,
+                    n_head,
+                    len,
+                    head_dim / Q8_0_BLOCK_SIZE,
+                    Q8_0_BLOCK_SIZE,
+                ))?;
+                let scales = scales.narrow(2, start, len)?.unsqueeze(D::Minus1)?;
+                blocks
+                    .broadcast_mul(&scales)?
+                    .reshape((b_sz, n_head, len, head_dim))
+            }
+        }
+    }
+
+    /// Copy of the first `len` entries in a buffer of `capacity` entries.
+    fn resized(&self, dtype: KvCacheDType, capacity: usize, len: usize) -> Result<Self> {
+        let (b_sz, n_head, _, head_dim) = self.data.dims4()?;
+        let buffer = Self::zeros(
+            dtype,
+            (b_sz, n_head, capacity, head_dim),
+            self.data.device(),
+        )?;
+        if len > 0 {
+            buffer
+                .data
+                .slice_set(&self.data.narrow(2, 0, len)?.contiguous()?, 2, 0)?;
+            if let (Some(scales), Some(old_scales)) = (&buffer.scales, &self.scales) {
+                scales.slice_set(&old_scales.narrow(2, 0, len)?.contiguous()?, 2, 0)?;
+            }
+        }
+        Ok(buffer)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct KvCache {
+    config: KvCacheConfig,
+    max_capacity: usize,
+    k: Option<CacheBuffer>,
+    v: Option<CacheBuffer>,
+    len: usize,
+    // Clones share the buffers, a writer sharing them copies them first.
+    owner: Arc<()>,
+}
+
+impl KvCache {
+    pub fn new(config: KvCacheConfig, max_capacity: usize, head_dim: usize) -> Result<Self> {
+        if config.dtype == KvCacheDType::Q8_0 && !head_dim.is_multiple_of(Q8_0_BLOCK_SIZE) {
+            candle_core::bail!("q8_0 KV cache needs a head size multiple of {Q8_0_BLOCK_SIZE}")
+        }
+        Ok(Self {
+            config,
+            max_capacity,
+            k: None,
+            v: None,
+            len: 0,
+            owner: Arc::new(()),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn usage(&self) -> Result<KvCacheUsage> {
+        let capacity = match &self.k {
+            None => 0,
+            Some(k) => k.capacity()?,
+        };
+        let bytes = self.k.as_ref().map_or(0, CacheBuffer::bytes)
+            + self.v.as_ref().map_or(0, CacheBuffer::bytes);
+        Ok(KvCacheUsage {
+            tokens: self.len,
+            capacity,
+            bytes,
+        })
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
+    }
+
+    pub fn clear(&mut self) {
+        self.k = None;
+        self.v = None;
+        self.len = 0;
+        self.owner = Arc::new(());
+    }
+
+    /// Makes sure the buffers hold at least `needed` entries and are not shared.
+    fn reserve(&mut self, needed: usize, k: &Tensor) -> Result<()> {
+        let capacity = match &self.k {
+            None => 0,
+            Some(k) => k.capacity()?,
+        };
+        let shared = Arc::strong_count(&self.owner) > 1;
+        if needed <= capacity && !shared {
+            return Ok(());
+        }
+
+        let capacity = if shared {
+            (needed + UNSHARE_HEADROOM)
+                .min(self.max_capacity)
+                .max(needed)
+        } else {
+            let initial = self.config.capacity.unwrap_or(self.max_capacity);
+            (capacity * 2)
+                .max(initial)
+                .min(self.max_capacity)
+                .max(needed)
+        };
+        let (k_buffer, v_buffer) = match (&self.k, &self.v) {
+            (Some(k), Some(v)) => (
+                k.resized(self.config.dtype, capacity, self.len)?,
+                v.resized(self.config.dtype, capacity, self.len)?,
+            ),
+            _ => {
+                let (b_sz, n_head, _, head_dim) = k.dims4()?;
+                let shape = (b_sz, n_head, capacity, head_dim);
+                (
+                    CacheBuffer::zeros(self.config.dtype, shape, k.device())?,
+                    CacheBuffer::zeros(self.config.dtype, shape, k.device())?,
+                )
+            }
+        };
+        self.k = Some(k_buffer);
+        self.v = Some(v_buffer);
+        self.owner = Arc::new(());
+        Ok(())
+    }
+
+    /// Writes `k` and `v` at `offset`, dropping any entries after it, and
+    /// returns all the cached keys and values.
+    pub fn append(&mut self, offset: usize, k: &Tensor, v: &Tensor) -> Result<(Tensor, Tensor)> {
+        let offset = offset.min(self.len);
+        let needed = offset + k.dim(2)?;
+        self.reserve(needed, k)?;
+        let (Some(k_buffer), Some(v_buffer)) = (self.k.as_mut(), self.v.as_mut()) else {
+            candle_core::bail!("KV cache is not allocated")
+        };
+        k_buffer.write(offset, k)?;
+        v_buffer.write(offset, v)?;
+        self.len = needed;
+        Ok((k_buffer.read(0, needed)?, v_buffer.read(0, needed)?))
+    }
+
+    /// Drops `discard` entries after the first `keep` and moves the later ones
+    /// back, passing the moved keys through `rotate_keys`.
+    pub fn shift(
+        &mut self,
+        keep: usize,
+        discard: usize,
+        rotate_keys: impl Fn(&Tensor) -> Result<Tensor>,
+    ) -> Result<()> {
+        let keep = keep.min(self.len);
+        let tail_len = self.len.saturating_sub(keep + discard);
+        if tail_len == 0 {
+            self.len = keep;
+            return Ok(());
+        }
+        let Some(k) = self.k.clone() else {
+            return Ok(());
+        };
+        self.reserve(self.len, &k.data)?;
+        let (Some(k_buffer), Some(v_buffer)) = (self.k.as_mut(), self.v.as_mut()) else {
+            candle_core::bail!("KV cache is not allocated")
+        };
+        let k_tail = rotate_keys(&k_buffer.read(keep + discard, tail_len)?)?;
+        // The read can alias the buffer, the copy keeps the write from overlapping it.
+        let v_tail = v_buffer.read(keep + discard, tail_len)?.copy()?;
+        k_buffer.write(keep, &k_tail)?;
+        v_buffer.write(keep, &v_tail)?;
+        self.len = keep + tail_len;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(len: usize) -> Result<Tensor> {
+        Tensor::ones((1, 2, len, 4), DType::F32, &Device::Cpu)
+    }
+
+    #[test]
+    fn clones_copy_only_the_cached_entries() -> Result<()> {
+        let mut cache = KvCache::new(KvCacheConfig::default(), 4096, 4)?;
+        let prompt = entries(10)?;
+        cache.append(0, &prompt, &prompt)?;
+        assert_eq!(cache.usage()?.capacity, 4096);
+
+        let mut clone = cache.clone();
+        let token = entries(1)?;
+        let (k, _) = clone.append(10, &token, &token)?;
+        assert_eq!(k.dim(2)?, 11);
+        assert_eq!(clone.usage()?.capacity, 11 + UNSHARE_HEADROOM);
+        assert_eq!(cache.usage()?.capacity, 4096);
+        Ok(())
+    }
+}
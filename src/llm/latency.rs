@@ -0,0 +1,111 @@
+//! Timing of a single request, measured inside the stream.
+
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// Nearest rank percentile `p` of the `sorted` values, the default when empty.
+pub fn percentile<T: Copy + Default>(sorted: &[T], p: f64) -> T {
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    sorted.get(rank.max(1) - 1).copied().unwrap_or_default()
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LatencyMetrics {
+    /// From the creation of the stream to the start of `invoke`.
+    pub queue: Duration,
+    pub tokenization: Duration,
+    pub prefill: Duration,
+    /// From the creation of the stream to the first sampled token, queue included.
+    pub time_to_first_token: Duration,
+    /// From the first to the last sampled token.
+    pub decode: Duration,
+    pub inter_token_p50: Duration,
+    pub inter_token_p95: Duration,
+    pub inter_token_p99: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct LatencyTracker {
+    created_at: Instant,
+    invoked_at: Option<Instant>,
+    tokenization: Duration,
+    prefill: Duration,
+    first_token_at: Option<Instant>,
+    last_token_at: Option<Instant>,
+    inter_token: Vec<Duration>,
+}
+
+impl Default for LatencyTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LatencyTracker {
+    pub fn new() -> Self {
+        Self {
+            created_at: Instant::now(),
+            invoked_at: None,
+            tokenization: Duration::ZERO,
+            prefill: Duration::ZERO,
+            first_token_at: None,
+            last_token_at: None,
+            inter_token: vec![],
+        }
+    }
+
+    pub fn invoked(&mut self) {
+        self.invoked_at = Some(Instant::now());
+    }
+
+    pub fn tokenized(&mut self, tokenization: Duration) {
+        self.tokenization = tokenization;
+    }
+
+    pub fn prefilled(&mut self, prefill: Duration) {
+        self.prefill = prefill;
+    }
+
+    pub fn token(&mut self) {
+        let now = Instant::now();
+        if let Some(last_token_at) = self.last_token_at {
+            self.inter_token.push(now - last_token_at);
+        }
+        self.first_token_at.get_or_insert(now);
+        self.last_token_at = Some(now);
+    }
+
+    pub fn metrics(&self) -> LatencyMetrics {
+        let mut inter_token = self.inter_token.clone();
+        inter_token.sort();
+        let since = |start: Option<Instant>, end: Option<Instant>| match (start, end) {
+            (Some(start), Some(end)) => end - start,
+            _ => Duration::ZERO,
+        };
+        LatencyMetrics {
+            queue: since(Some(self.created_at), self.invoked_at),
+            tokenization: self.tokenization,
+            prefill: self.prefill,
+            time_to_first_token: since(Some(self.created_at), self.first_token_at),
+            decode: since(self.first_token_at, self.last_token_at),
+            inter_token_p50: percentile(&inter_token, 0.5),
+            inter_token_p95: percentile(&inter_token, 0.95),
+            inter_token_p99: percentile(&inter_token, 0.99),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentile_takes_the_nearest_rank() {
+        let sorted = [1., 2., 3., 4.];
+        assert_eq!(percentile(&sorted, 0.5), 2.);
+        assert_eq!(percentile(&sorted, 0.95), 4.);
+        assert_eq!(percentile(&sorted, 0.), 1.);
+        assert_eq!(percentile::<f64>(&[], 0.5), 0.);
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+
+use candle_core::{Result, Tensor};
+use tokenizers::Tokenizer;
+
+use super::dry_sampler::DrySampler;
+use super::wavvy_chat_stream::{WavvyArgs, WavvyError};
+
+/// Tokens seen so far by the generation, split between the prompt and the
+/// completion sampled after it.
+pub struct TokenHistory<'a> {
+    pub prompt: &'a [u32],
+    pub completion: &'a [u32],
+}
+
+impl TokenHistory<'_> {
+    pub fn all(&self) -> Vec<u32> {
+        [self.prompt, self.completion].concat()
+    }
+}
+
+/// A stage of the sampler chain, run on the logits of every step before the
+/// next token is sampled.
+pub trait LogitProcessor: Send {
+    fn process(&mut self, logits: Tensor, history: &TokenHistory) -> Result<Tensor>;
+
+    /// Copy of the stage in its current state, every choice of a multi-completion
+    /// request runs its own copy.
+    fn boxed_clone(&self) -> Box<dyn LogitProcessor>;
+}
+
+#[derive(Clone)]
+pub struct RepeatPenalty {
+    pub penalty: f32,
+    pub last_n: usize,
+}
+
+impl LogitProcessor for RepeatPenalty {
+    fn process(&mut self, logits: Tensor, history: &TokenHistory) -> Result<Tensor> {
+        let start_at = history.completion.len().saturating_sub(self.last_n);
+        candle_transformers::utils::apply_repeat_penalty(
+            &logits,
+            self.penalty,
+            &history.completion[start_at..],
+        )
+    }
+
+    fn boxed_clone(&self) -> Box<dyn LogitProcessor> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Clone)]
+pub struct LogitBias {
+    pub bias: HashMap<u32, f32>,
+}
+
+impl LogitProcessor for LogitBias {
+    fn process(&mut self, logits: Tensor, _history: &TokenHistory) -> Result<Tensor> {
+        let device = logits.device().clone();
+        let mut logits = logits.to_dtype(candle_core::DType::F32)?.to_vec1::<f32>()?;
+        for (token, bias) in &self.bias {
+            if let Some(logit) = logits.get_mut(*token as usize) {
+                *logit += bias;
+            }
+        }
+        let logits_len = logits.len();
+        Tensor::from_vec(logits, logits_len, &device)
+    }
+
+    fn boxed_clone(&self) -> Box<dyn LogitProcessor> {
+        Box::new(self.clone())
+    }
+}
+
+/// Ordered list of logit processors. The built-in stages come from `WavvyArgs`,
+/// custom stages run after them. Clones copy every stage, so the choices of a
+/// multi-completion request never share processor state.
+#[derive(Default)]
+pub struct SamplerChain {
+    processors: Vec<Box<dyn LogitProcessor>>,
+}
+
+impl Clone for SamplerChain {
+    fn clone(&self) -> Self {
+        Self {
+            processors: self.processors.iter().map(|p| p.boxed_clone()).collect(),
+        }
+    }
+}
+
+impl SamplerChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_args(
+        args: &WavvyArgs,
+        tokenizer: &Tokenizer,
+    ) -> std::result::Result<Self, WavvyError> {
+        let mut chain = Self::new();
+        if args.repeat_penalty != 1. {
+            chain.push(RepeatPenalty {
+                penalty: args.repeat_penalty,
+                last_n: args.repeat_last_n,
+            });
+        }
+        if args.dry_multiplier > 0. {
+            chain.push(DrySampler::new(args, tokenizer)?);
+        }
+        if !args.logit_bias.is_empty() {
+            chain.push(LogitBias {
+                bias: args.logit_bias.clone(),
+            });
+        }
+        Ok(chain)
+    }
+
+    pub fn push(&mut self, processor: impl LogitProcessor + 'static) {
+        self.processors.push(Box::new(processor));
+    }
+
+    pub fn append(&mut self, other: SamplerChain) {
+        self.processors.extend(other.processors);
+    }
+
+    pub fn len(&self) -> usize {
+        self.processors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.processors.is_empty()
+    }
+
+    pub fn process(&mut self, logits: Tensor, history: &TokenHistory) -> Result<Tensor> {
+        let mut logits = logits;
+        for processor in self.processors.iter_mut() {
+            logits = processor.process(logits, history)?;
+        }
+        Ok(logits)
+    }
+}
@@ -1,4 +1,17 @@
+pub mod banned_strings;
+pub mod beam_search;
+pub mod cancellation;
+pub mod dry_sampler;
+pub mod kv_cache;
+pub mod latency;
+pub mod logit_processor;
 pub mod model_builder;
+pub mod prompt_lookup;
+pub mod quantized_qwen2;
+pub mod scoring;
+pub mod speculative;
 pub mod token_output;
+pub mod wavvy_args_builder;
 pub mod wavvy_chat;
 pub mod wavvy_chat_stream;
+pub mod worker_stream;
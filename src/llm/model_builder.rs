@@ -1,5 +1,6 @@
+use super::quantized_qwen2::ModelWeights;
+use super::wavvy_chat_stream::WavvyError;
 use candle_core::{quantized::gguf_file, Device};
-use candle_transformers::models::quantized_qwen2::ModelWeights;
 use tokenizers::Tokenizer;
 
 #[derive(Debug)]
@@ -18,16 +19,23 @@ impl ModelBuilder {
         }
     }
 
-    pub fn load_tokenizer(&self) -> Tokenizer {
-        Tokenizer::from_file(std::path::PathBuf::from(&self.tokenizer_path)).unwrap()
+    #[tracing::instrument(skip_all, fields(path = %self.tokenizer_path))]
+    pub fn load_tokenizer(&self) -> Result<Tokenizer, WavvyError> {
+        Tokenizer::from_file(std::path::PathBuf::from(&self.tokenizer_path))
+            .map_err(WavvyError::TokenizerError)
     }
 
-    pub fn load_model(&self) -> ModelWeights {
+    #[tracing::instrument(skip_all, fields(path = %self.model_path))]
+    pub fn load_model(&self) -> Result<ModelWeights, WavvyError> {
+        let model_load_error = |source| WavvyError::ModelLoadError {
+            path: self.model_path.clone(),
+            source,
+        };
         let model_path = std::path::PathBuf::from(&self.model_path);
-        let mut file = std::fs::File::open(&model_path).unwrap();
-        let model: gguf_file::Content = gguf_file::Content::read(&mut file)
-            .map_err(|e| e.with_path(model_path))
-            .unwrap();
-        ModelWeights::from_gguf(model, &mut file, &self.device).unwrap()
+        let mut file = std::fs::File::open(&model_path)
+            .map_err(|e| model_load_error(candle_core::Error::from(e)))?;
+        let model: gguf_file::Content =
+            gguf_file::Content::read(&mut file).map_err(model_load_error)?;
+        ModelWeights::from_gguf(model, &mut file, &self.device).map_err(model_load_error)
     }
 }
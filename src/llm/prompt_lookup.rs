@@ -0,0 +1,70 @@
+/// Draft-free speculation: proposes the tokens that followed the latest n-gram
+/// the last time it appeared in the context.
+pub struct PromptLookup {
+    max_ngram: usize,
+    num_tokens: usize,
+}
+
+impl PromptLookup {
+    pub fn new(max_ngram: usize, num_tokens: usize) -> Self {
+        Self {
+            max_ngram: max_ngram.max(1),
+            num_tokens,
+        }
+    }
+
+    pub fn propose(&self, context: &[u32]) -> Vec<u32> {
+        for ngram_size in (1..=self.max_ngram).rev() {
+            if context.len() <= ngram_size {
+                continue;
+            }
+            let ngram = &context[context.len() - ngram_size..];
+            // Most recent earlier occurrence first, it is the likeliest to be copied.
+            for start in (0..context.len() - ngram_size).rev() {
+                if &context[start..start + ngram_size] != ngram {
+                    continue;
+                }
+                let from = start + ngram_size;
+                let to = (from + self.num_tokens).min(context.len());
+                if from < to {
+                    return context[from..to].to_vec();
+                }
+            }
+        }
+        vec![]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn longest_ngram_wins() {
+        let lookup = PromptLookup::new(3, 2);
+        assert_eq!(lookup.propose(&[1, 2, 3, 9, 5, 3, 8, 1, 2, 3]), vec![9, 5]);
+        let lookup = PromptLookup::new(1, 2);
+        assert_eq!(lookup.propose(&[1, 2, 3, 9, 5, 3, 8, 1, 2, 3]), vec![8, 1]);
+    }
+
+    #[test]
+    fn most_recent_occurrence_wins() {
+        let lookup = PromptLookup::new(2, 1);
+        assert_eq!(lookup.propose(&[1, 2, 7, 1, 2, 8, 1, 2]), vec![8]);
+    }
+
+    #[test]
+    fn proposal_stops_at_the_end_of_the_context() {
+        let lookup = PromptLookup::new(1, 3);
+        assert_eq!(lookup.propose(&[4, 5, 4]), vec![5, 4]);
+    }
+
+    #[test]
+    fn nothing_to_propose() {
+        let lookup = PromptLookup::new(3, 2);
+        assert!(lookup.propose(&[]).is_empty());
+        assert!(lookup.propose(&[1]).is_empty());
+        assert!(lookup.propose(&[1, 2, 3, 4]).is_empty());
+        assert!(PromptLookup::new(3, 0).propose(&[1, 2, 1]).is_empty());
+    }
+}
@@ -0,0 +1,200 @@
+//! Log-likelihood of given text under the model, without sampling.
+
+use std::ops::Range;
+
+use candle_core::{Device, Tensor, D};
+use tokenizers::Tokenizer;
+
+use super::quantized_qwen2::ModelWeights as Qwen2;
+use super::wavvy_chat_stream::WavvyError;
+
+#[derive(Debug, Clone, Default)]
+pub struct SequenceScore {
+    /// The scored tokens, the prompt is not part of them.
+    pub tokens: Vec<u32>,
+    pub token_log_probs: Vec<f32>,
+    pub log_prob: f32,
+}
+
+impl SequenceScore {
+    pub fn mean_log_prob(&self) -> f32 {
+        if self.tokens.is_empty() {
+            0.
+        } else {
+            self.log_prob / self.tokens.len() as f32
+        }
+    }
+
+    pub fn perplexity(&self) -> f32 {
+        (-self.mean_log_prob()).exp()
+    }
+}
+
+pub struct Scorer {
+    model: Qwen2,
+    tokenizer: Tokenizer,
+    device: Device,
+}
+
+impl Scorer {
+    pub fn new(model: Qwen2, tokenizer: Tokenizer, device: &Device) -> Self {
+        Self {
+            model,
+            tokenizer,
+            device: device.clone(),
+        }
+    }
+
+    pub fn context_length(&self) -> usize {
+        self.model.context_length()
+    }
+
+    fn encode(&self, text: &str, add_special_tokens: bool) -> Result<Vec<u32>, WavvyError> {
+        self.tokenizer
+            .encode(text, add_special_tokens)
+            .map(|tokens| tokens.get_ids().to_vec())
+            .map_err(WavvyError::TokenizerError)
+    }
+
+    /// Log-probabilities of `continuation` following `prompt`, in one forward pass.
+    pub fn score(&mut self, prompt: &str, continuation: &str) -> Result<SequenceScore, WavvyError> {
+        let prompt_tokens = self.encode(prompt, true)?;
+        let continuation_tokens = self.encode(continuation, false)?;
+        if prompt_tokens.is_empty() {
+            return Err(WavvyError::InvalidArgument(String::from(
+                "the prompt has no tokens to condition the continuation on",
+            )));
+        }
+        let context_length = self.context_length();
+        let tokens = [prompt_tokens.as_slice(), &continuation_tokens].concat();
+        if tokens.len() > context_length {
+            return Err(WavvyError::ContextOverflow {
+                prompt_tokens: tokens.len(),
+                max_prompt_tokens: context_length,
+            });
+        }
+
+        let token_log_probs = self.log_probs(&tokens, prompt_tokens.len())?;
+        Ok(SequenceScore {
+            log_prob: token_log_probs.iter().sum(),
+            tokens: continuation_tokens,
+            token_log_probs,
+        })
+    }
+
+    /// Scores every token of `text` but the first with a sliding window of
+    /// `window` tokens, moved by `stride` tokens, fewer than `window`. Each token
+    /// is scored once, with as much of the window before it as context.
+    pub fn perplexity(
+        &mut self,
+        text: &str,
+        window: usize,
+        stride: usize,
+    ) -> Result<SequenceScore, WavvyError> {
+        if window < 2 || window > self.context_length() {
+            return Err(WavvyError::InvalidArgument(format!(
+                "window must be between 2 and {} tokens, got {window}",
+                self.context_length()
+            )));
+        }
+        // Windows have to overlap, the first token of a window has no context in it.
+        if stride == 0 || stride >= window {
+            return Err(WavvyError::InvalidArgument(format!(
+                "stride must be between 1 and {} tokens, below the window, got {stride}",
+                window - 1
+            )));
+        }
+
+        let tokens = self.encode(text, true)?;
+        let mut token_log_probs = Vec::with_capacity(tokens.len().saturating_sub(1));
+        for (range, start) in perplexity_windows(tokens.len(), window, stride) {
+            token_log_probs.extend(self.log_probs(&tokens[range], start)?);
+        }
+
+        Ok(SequenceScore {
+            log_prob: token_log_probs.iter().sum(),
+            tokens: tokens.get(1..).unwrap_or_default().to_vec(),
+            token_log_probs,
+        })
+    }
+
+    /// Log-probabilities of `tokens[start..]`, each given the tokens before it.
+    fn log_probs(&mut self, tokens: &[u32], start: usize) -> Result<Vec<f32>, WavvyError> {
+        if start >= tokens.len() {
+            return Ok(vec![]);
+        }
+        self.model.clear_kv_cache();
+        // The last token is only predicted, the logits of position `i` predict token `i + 1`.
+        let input = Tensor::new(&tokens[..tokens.len() - 1], &self.device)
+            .and_then(|input| input.unsqueeze(0))
+            .map_err(WavvyError::ForwardError)?;
+        let logits = self
+            .model
+            .forward_from(&input, 0, start - 1)
+            .and_then(|logits| logits.squeeze(0))
+            .map_err(WavvyError::ForwardError)?;
+        let targets = Tensor::new(&tokens[start..], &self.device)
+            .and_then(|targets| targets.unsqueeze(1))
+            .map_err(WavvyError::SamplingError)?;
+        candle_nn::ops::log_softmax(&logits, D::Minus1)
+            .and_then(|log_probs| log_probs.gather(&targets, 1))
+            .and_then(|log_probs| log_probs.squeeze(1))
+            .and_then(|log_probs| log_probs.to_dtype(candle_core::DType::F32))
+            .and_then(|log_probs| log_probs.to_vec1::<f32>())
+            .map_err(WavvyError::SamplingError)
+    }
+}
+
+/// Token ranges of the sliding windows of `perplexity`, each with the offset of
+/// the first token it scores. Needs `0 < stride < window`.
+fn perplexity_windows(
+    num_tokens: usize,
+    window: usize,
+    stride: usize,
+) -> Vec<(Range<usize>, usize)> {
+    let mut windows = vec![];
+    let mut scored = 1;
+    let mut begin = 0;
+    while scored < num_tokens {
+        let end = (begin + window).min(num_tokens);
+        if end > scored {
+            windows.push((begin..end, scored - begin));
+            scored = end;
+        }
+        begin += stride;
+    }
+    windows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_overlap_by_window_minus_stride() {
+        assert_eq!(
+            perplexity_windows(10, 4, 2),
+            vec![(0..4, 1), (2..6, 2), (4..8, 2), (6..10, 2)]
+        );
+        assert_eq!(perplexity_windows(5, 8, 4), vec![(0..5, 1)]);
+    }
+
+    #[test]
+    fn every_token_but_the_first_is_scored_once_with_context() {
+        for (num_tokens, window, stride) in [(10, 4, 3), (17, 5, 1), (33, 8, 7), (2, 2, 1)] {
+            let mut scored = vec![];
+            for (range, start) in perplexity_windows(num_tokens, window, stride) {
+                assert!(start >= 1 && start < range.len());
+                assert!(range.len() <= window);
+                scored.extend(range.start + start..range.end);
+            }
+            assert_eq!(scored, (1..num_tokens).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn short_texts_have_nothing_to_score() {
+        assert!(perplexity_windows(0, 4, 2).is_empty());
+        assert!(perplexity_windows(1, 4, 2).is_empty());
+    }
+}
@@ -0,0 +1,170 @@
+use candle_core::{DType, Device, Result, Tensor};
+use rand::{distributions::Distribution, rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use super::quantized_qwen2::ModelWeights as Qwen2;
+use super::wavvy_chat_stream::WavvyArgs;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SpeculativeStats {
+    pub draft_tokens: usize,
+    pub accepted_tokens: usize,
+}
+
+impl SpeculativeStats {
+    pub fn acceptance_rate(&self) -> f64 {
+        if self.draft_tokens == 0 {
+            0.
+        } else {
+            self.accepted_tokens as f64 / self.draft_tokens as f64
+        }
+    }
+}
+
+/// Probabilities the next token is sampled from, following the temperature,
+/// top-k and top-p settings of `args`.
+pub fn sampling_probs(logits: &Tensor, args: &WavvyArgs) -> Result<Vec<f32>> {
+    let logits = logits.to_dtype(DType::F32)?;
+    if args.temperature <= 0. {
+        let logits = logits.to_vec1::<f32>()?;
+        let mut probs = vec![0f32; logits.len()];
+        if let Some((index, _)) = logits
+            .iter()
+            .enumerate()
+            .max_by(|(_, u), (_, v)| u.total_cmp(v))
+        {
+            probs[index] = 1.;
+        }
+        return Ok(probs);
+    }
+
+    let logits = (logits / args.temperature)?;
+    let mut probs = candle_nn::ops::softmax_last_dim(&logits)?.to_vec1::<f32>()?;
+    let mut argsort_indices = (0..probs.len()).collect::<Vec<_>>();
+    argsort_indices.sort_by(|&i, &j| probs[j].total_cmp(&probs[i]));
+
+    if let Some(k) = args.top_k {
+        for index in argsort_indices.iter().skip(k) {
+            probs[*index] = 0.;
+        }
+    }
+    if let Some(p) = args.top_p.filter(|p| *p > 0. && *p < 1.) {
+        let mut cumsum = 0.;
+        for index in &argsort_indices {
+            if cumsum >= p as f32 {
+                probs[*index] = 0.;
+            } else {
+                cumsum += probs[*index];
+            }
+        }
+    }
+
+    let sum = probs.iter().sum::<f32>();
+    if sum > 0. {
+        probs.iter_mut().for_each(|prob| *prob /= sum);
+    }
+    Ok(probs)
+}
+
+/// Rejection sampler that accepts or replaces drafted tokens so that the output
+/// follows the target model distribution exactly.
+pub struct SpeculativeSampler {
+    rng: StdRng,
+    pub stats: SpeculativeStats,
+}
+
+impl SpeculativeSampler {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            stats: SpeculativeStats::default(),
+        }
+    }
+
+    pub fn sample(&mut self, probs: &[f32]) -> Result<u32> {
+        let distr =
+            rand::distributions::WeightedIndex::new(probs).map_err(candle_core::Error::wrap)?;
+        Ok(distr.sample(&mut self.rng) as u32)
+    }
+
+    /// Returns `None` when `token` is accepted, otherwise the token sampled in its
+    /// place. A missing draft distribution means the token was proposed with
+    /// certainty.
+    pub fn verify(
+        &mut self,
+        token: u32,
+        target: &[f32],
+        draft: Option<&[f32]>,
+    ) -> Result<Option<u32>> {
+        let draft_prob = |index: usize| match draft {
+            Some(draft) => draft.get(index).copied().unwrap_or(0.),
+            None => f32::from(index == token as usize),
+        };
+
+        let p = target.get(token as usize).copied().unwrap_or(0.);
+        let q = draft_prob(token as usize);
+        if q > 0. && self.rng.gen::<f32>() < (p / q).min(1.) {
+            return Ok(None);
+        }
+
+        let residual: Vec<f32> = target
+            .iter()
+            .enumerate()
+            .map(|(index, p)| (p - draft_prob(index)).max(0.))
+            .collect();
+        if residual.iter().sum::<f32>() <= 0. {
+            return self.sample(target).map(Some);
+        }
+        self.sample(&residual).map(Some)
+    }
+}
+
+/// Small model proposing the tokens the main model verifies. It must share the
+/// tokenizer of the main model.
+#[derive(Clone)]
+pub struct DraftModel {
+    model: Qwen2,
+    cache_len: usize,
+}
+
+impl DraftModel {
+    pub fn new(model: Qwen2) -> Self {
+        Self {
+            model,
+            cache_len: 0,
+        }
+    }
+
+    pub fn propose(
+        &mut self,
+        context: &[u32],
+        num_tokens: usize,
+        args: &WavvyArgs,
+        sampler: &mut SpeculativeSampler,
+        device: &Device,
+    ) -> Result<(Vec<u32>, Vec<Vec<f32>>)> {
+        let mut tokens = Vec::with_capacity(num_tokens);
+        let mut probs = Vec::with_capacity(num_tokens);
+        // Feed whatever the draft has not seen yet, the whole prompt on the first call.
+        let start = self.cache_len.min(context.len().saturating_sub(1));
+        let mut input = context[start..].to_vec();
+        self.cache_len = start;
+        for _ in 0..num_tokens {
+            let x = Tensor::new(input.as_slice(), device)?.unsqueeze(0)?;
+            let logits = self.model.forward(&x, self.cache_len)?.squeeze(0)?;
+            self.cache_len += input.len();
+
+            let token_probs = sampling_probs(&logits, args)?;
+            let token = sampler.sample(&token_probs)?;
+            tokens.push(token);
+            probs.push(token_probs);
+            input = vec![token];
+        }
+        Ok((tokens, probs))
+    }
+
+    pub fn rollback(&mut self, len: usize) -> Result<()> {
+        self.cache_len = self.cache_len.min(len);
+        self.model.truncate_kv_cache(self.cache_len)
+    }
+}
@@ -1,4 +1,4 @@
-use candle_core::Result;
+use tokenizers::Result;
 
 pub struct TokenOutput {
     tokenizer: tokenizers::Tokenizer,
@@ -21,17 +21,28 @@ impl TokenOutput {
         self.tokenizer
     }
 
-    fn decode(&self, tokens: &[u32]) -> Result<String> {
-        match self.tokenizer.decode(tokens, true) {
-            Ok(str) => Ok(str),
-            Err(err) => candle_core::bail!("cannot decode: {err}"),
-        }
+    pub fn decode(&self, tokens: &[u32]) -> Result<String> {
+        self.tokenizer.decode(tokens, true)
     }
 
     pub fn total_tokens(&self) -> usize {
         self.tokens.len()
     }
 
+    pub fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+
+    pub fn push_token(&mut self, token: u32) {
+        self.tokens.push(token);
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.tokens.truncate(len);
+        self.prev_index = self.prev_index.min(len);
+        self.current_index = self.current_index.min(len);
+    }
+
     pub fn next_token(&mut self, token: u32) -> Result<Option<String>> {
         let prev_text = if self.tokens.is_empty() {
             String::new()
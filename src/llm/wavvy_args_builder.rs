@@ -0,0 +1,204 @@
+//! Validated construction of `WavvyArgs`. Its `Default` is the one source of
+//! defaults, the CLI flags included.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::kv_cache::KvCacheDType;
+use super::wavvy_chat_stream::{WavvyArgs, WavvyError};
+
+macro_rules! setters {
+    ($($method:ident => $field:ident: $ty:ty),* $(,)?) => {
+        $(pub fn $method(mut self, $field: $ty) -> Self {
+            self.args.$field = $field;
+            self
+        })*
+    };
+}
+
+macro_rules! option_setters {
+    ($($method:ident => $field:ident: $ty:ty),* $(,)?) => {
+        $(pub fn $method(mut self, $field: $ty) -> Self {
+            self.args.$field = Some($field);
+            self
+        })*
+    };
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct WavvyArgsBuilder {
+    args: WavvyArgs,
+}
+
+impl WavvyArgs {
+    pub fn builder() -> WavvyArgsBuilder {
+        WavvyArgsBuilder::default()
+    }
+
+    /// Starts from these args, to override session defaults per request.
+    pub fn to_builder(&self) -> WavvyArgsBuilder {
+        WavvyArgsBuilder { args: self.clone() }
+    }
+
+    pub fn validate(&self) -> Result<(), WavvyError> {
+        let invalid = |message: String| Err(WavvyError::InvalidArgument(message));
+        if self.sample_len == 0 {
+            return invalid(String::from("sample_len must be at least 1"));
+        }
+        if !(self.temperature >= 0. && self.temperature.is_finite()) {
+            return invalid(format!(
+                "temperature must be a finite number of at least 0, got {}",
+                self.temperature
+            ));
+        }
+        if let Some(top_p) = self.top_p {
+            if !(top_p > 0. && top_p <= 1.) {
+                return invalid(format!("top_p must be in (0, 1], got {top_p}"));
+            }
+        }
+        if self.top_k == Some(0) {
+            return invalid(String::from("top_k must be at least 1"));
+        }
+        if self.prefill_chunk_size == Some(0) {
+            return invalid(String::from("prefill_chunk_size must be at least 1"));
+        }
+        if !(self.repeat_penalty > 0. && self.repeat_penalty.is_finite()) {
+            return invalid(format!(
+                "repeat_penalty must be a finite number above 0, got {}",
+                self.repeat_penalty
+            ));
+        }
+        if !(self.dry_multiplier >= 0. && self.dry_multiplier.is_finite()) {
+            return invalid(format!(
+                "dry_multiplier must be a finite number of at least 0, got {}",
+                self.dry_multiplier
+            ));
+        }
+        if !(self.dry_base >= 1. && self.dry_base.is_finite()) {
+            return invalid(format!(
+                "dry_base must be a finite number of at least 1, got {}",
+                self.dry_base
+            ));
+        }
+        if let Some((token, bias)) = self.logit_bias.iter().find(|(_, bias)| bias.is_nan()) {
+            return invalid(format!("logit_bias of token {token} is {bias}"));
+        }
+        if self.prompt_lookup_num_tokens > 0 && self.prompt_lookup_max_ngram == 0 {
+            return invalid(String::from(
+                "prompt_lookup_max_ngram must be at least 1 with prompt lookup",
+            ));
+        }
+        if self.num_beams == 0 {
+            return invalid(String::from("num_beams must be at least 1"));
+        }
+        if !self.length_penalty.is_finite() {
+            return invalid(format!(
+                "length_penalty must be finite, got {}",
+                self.length_penalty
+            ));
+        }
+        if self.n == 0 {
+            return invalid(String::from("n must be at least 1"));
+        }
+        let best_of = self.best_of.unwrap_or(self.n);
+        if best_of < self.n {
+            return invalid(format!(
+                "best_of ({best_of}) must not be less than n ({})",
+                self.n
+            ));
+        }
+        if self.channel_capacity == 0 {
+            return invalid(String::from("channel_capacity must be at least 1"));
+        }
+        Ok(())
+    }
+}
+
+impl WavvyArgsBuilder {
+    setters!(
+        with_sample_len => sample_len: usize,
+        with_temperature => temperature: f64,
+        with_seed => seed: u64,
+        with_split_prompt => split_prompt: bool,
+        with_repeat_penalty => repeat_penalty: f32,
+        with_repeat_last_n => repeat_last_n: usize,
+        with_dry_multiplier => dry_multiplier: f32,
+        with_dry_base => dry_base: f32,
+        with_dry_allowed_length => dry_allowed_length: usize,
+        with_dry_penalty_last_n => dry_penalty_last_n: usize,
+        with_dry_sequence_breakers => dry_sequence_breakers: Vec<String>,
+        with_logit_bias => logit_bias: HashMap<u32, f32>,
+        with_banned_strings => banned_strings: Vec<String>,
+        with_num_draft_tokens => num_draft_tokens: usize,
+        with_prompt_lookup_num_tokens => prompt_lookup_num_tokens: usize,
+        with_prompt_lookup_max_ngram => prompt_lookup_max_ngram: usize,
+        with_num_beams => num_beams: usize,
+        with_length_penalty => length_penalty: f32,
+        with_early_stopping => early_stopping: bool,
+        with_n => n: usize,
+        with_channel_capacity => channel_capacity: usize,
+        with_context_shift => context_shift: bool,
+        with_sink_tokens => sink_tokens: usize,
+        with_kv_cache_dtype => kv_cache_dtype: KvCacheDType,
+    );
+
+    option_setters!(
+        with_top_p => top_p: f64,
+        with_top_k => top_k: usize,
+        with_prefill_chunk_size => prefill_chunk_size: usize,
+        with_best_of => best_of: usize,
+        with_max_duration => max_duration: Duration,
+        with_kv_cache_capacity => kv_cache_capacity: usize,
+    );
+
+    pub fn build(self) -> Result<WavvyArgs, WavvyError> {
+        self.args.validate()?;
+        Ok(self.args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_invalid(builder: WavvyArgsBuilder) -> bool {
+        matches!(builder.build(), Err(WavvyError::InvalidArgument(_)))
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert!(WavvyArgs::default().validate().is_ok());
+    }
+
+    #[test]
+    fn top_p_excludes_0_and_includes_1() {
+        assert!(is_invalid(WavvyArgs::builder().with_top_p(0.)));
+        assert!(is_invalid(WavvyArgs::builder().with_top_p(1.01)));
+        assert!(WavvyArgs::builder().with_top_p(1.).build().is_ok());
+        assert!(WavvyArgs::builder().with_top_p(0.01).build().is_ok());
+    }
+
+    #[test]
+    fn best_of_is_at_least_n() {
+        assert!(is_invalid(WavvyArgs::builder().with_n(3).with_best_of(2)));
+        assert!(WavvyArgs::builder()
+            .with_n(3)
+            .with_best_of(3)
+            .build()
+            .is_ok());
+        assert!(WavvyArgs::builder().with_n(3).build().is_ok());
+    }
+
+    #[test]
+    fn sample_len_is_at_least_1() {
+        assert!(is_invalid(WavvyArgs::builder().with_sample_len(0)));
+        assert!(WavvyArgs::builder().with_sample_len(1).build().is_ok());
+    }
+
+    #[test]
+    fn to_builder_keeps_the_args() {
+        let args = WavvyArgs::builder().with_n(2).build().unwrap();
+        let args = args.to_builder().with_sample_len(7).build().unwrap();
+        assert_eq!((args.n, args.sample_len), (2, 7));
+    }
+}
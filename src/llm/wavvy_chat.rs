@@ -1,8 +1,14 @@
-use crate::prompt_template::chat_template::Model;
+use crate::prompt_template::chat_template::{ChatTemplate, Model, TokenBreakdown};
 
-use super::wavvy_chat_stream::{ChatResponse, WavvyArgs, WavvyChatStream, WavvyError};
+use super::beam_search::{BeamHypothesis, BeamSearch};
+use super::cancellation::CancellationToken;
+use super::logit_processor::{LogitProcessor, SamplerChain};
+use super::quantized_qwen2::ModelWeights as Qwen2;
+use super::wavvy_chat_stream::{
+    ChatResponse, PrefillProgress, PrefillProgressCallback, WavvyArgs, WavvyChatStream, WavvyError,
+};
+use super::worker_stream::WorkerStream;
 use candle_core::Device;
-use candle_transformers::models::quantized_qwen2::ModelWeights as Qwen2;
 use futures::StreamExt;
 use tokenizers::Tokenizer;
 
@@ -11,6 +17,11 @@ pub struct WavvyChat {
     base_model: Qwen2,
     device: Device,
     tokenizer: Tokenizer,
+    sampler_chain: SamplerChain,
+    draft_model: Option<Qwen2>,
+    cancellation_token: Option<CancellationToken>,
+    prefill_progress: Option<PrefillProgressCallback>,
+    request_id: Option<String>,
     pub args: WavvyArgs,
 }
 
@@ -27,60 +38,151 @@ impl WavvyChat {
             base_model,
             device: device.clone(),
             tokenizer,
+            sampler_chain: SamplerChain::new(),
+            draft_model: None,
+            cancellation_token: None,
+            prefill_progress: None,
+            request_id: None,
             args: args.clone().unwrap_or_default(),
         }
     }
 
-    async fn process_invoke(self, prompt_str: String) -> Result<ChatResponse, WavvyError> {
-        let wavvy = WavvyChatStream::new(
+    pub fn with_logit_processor(mut self, processor: impl LogitProcessor + 'static) -> Self {
+        self.sampler_chain.push(processor);
+        self
+    }
+
+    pub fn with_draft_model(mut self, draft_model: Qwen2) -> Self {
+        self.draft_model = Some(draft_model);
+        self
+    }
+
+    /// Lets another thread stop `invoke`, which then returns the text generated
+    /// so far. Cancelling before the prompt is fully prefilled fails the request
+    /// with `WavvyError::Cancelled` instead, without usage.
+    pub fn with_cancellation_token(mut self, cancellation_token: CancellationToken) -> Self {
+        self.cancellation_token = Some(cancellation_token);
+        self
+    }
+
+    pub fn with_prefill_progress(
+        mut self,
+        prefill_progress: impl FnMut(PrefillProgress) + Send + 'static,
+    ) -> Self {
+        self.prefill_progress = Some(Box::new(prefill_progress));
+        self
+    }
+
+    /// Id of the request in traces, one is generated otherwise.
+    pub fn with_request_id(mut self, request_id: impl Into<String>) -> Self {
+        self.request_id = Some(request_id.into());
+        self
+    }
+
+    pub fn context_length(&self) -> usize {
+        self.base_model.context_length()
+    }
+
+    /// Prompt tokens of `template`, counted with the tokenizer only.
+    pub fn count_tokens(&self, template: &ChatTemplate) -> Result<usize, WavvyError> {
+        template.count_tokens(&self.tokenizer)
+    }
+
+    pub fn token_breakdown(&self, template: &ChatTemplate) -> Result<TokenBreakdown, WavvyError> {
+        template.token_breakdown(&self.tokenizer)
+    }
+
+    /// Tokens that can still be added to `template` while leaving room for
+    /// `sample_len` generated tokens.
+    pub fn remaining_budget(&self, template: &ChatTemplate) -> Result<usize, WavvyError> {
+        let prompt_tokens = self.count_tokens(template)?;
+        Ok(self
+            .context_length()
+            .saturating_sub(self.args.sample_len)
+            .saturating_sub(prompt_tokens))
+    }
+
+    fn into_stream(self) -> WavvyChatStream {
+        let mut wavvy = WavvyChatStream::new(
             self.model,
             self.base_model,
             self.tokenizer,
             &self.device,
             Some(self.args),
-        );
-        let mut wavvy_response = wavvy.invoke(prompt_str).unwrap();
-        let mut resp = ChatResponse {
-            content: String::default(),
-            prompt_tokens: 0,
-            completion_tokens: 0,
-            total_tokens: 0,
-        };
-
-        while let Some(item) = wavvy_response.next().await {
-            match item {
-                Ok(response) => {
-                    resp.content.push_str(response.content.as_str());
-                    resp.prompt_tokens = response.prompt_tokens;
-                    resp.completion_tokens = response.completion_tokens;
-                    resp.total_tokens = response.total_tokens;
-                }
-                Err(e) => {
-                    println!("Error: {}", e);
-                }
-            }
+        )
+        .with_sampler_chain(self.sampler_chain);
+        if let Some(cancellation_token) = self.cancellation_token {
+            wavvy = wavvy.with_cancellation_token(cancellation_token);
+        }
+        if let Some(prefill_progress) = self.prefill_progress {
+            wavvy = wavvy.with_prefill_progress(prefill_progress);
+        }
+        if let Some(request_id) = self.request_id {
+            wavvy = wavvy.with_request_id(request_id);
+        }
+        match self.draft_model {
+            Some(draft_model) => wavvy.with_draft_model(draft_model),
+            None => wavvy,
         }
-        Ok(resp)
+    }
+
+    /// Returns the `n` completions of the request, ordered by choice index. Runs
+    /// on the calling thread and needs no async runtime.
+    pub fn invoke_choices(self, prompt_str: String) -> Result<Vec<ChatResponse>, WavvyError> {
+        let mut choices = vec![];
+        for item in self.iter_invoke(prompt_str)? {
+            merge_choice(&mut choices, item?);
+        }
+        Ok(choices)
     }
 
     pub fn invoke(self, prompt_str: String) -> Result<ChatResponse, WavvyError> {
-        let runtime = tokio::runtime::Builder::new_multi_thread()
-            .enable_all()
-            .build()
-            .map_err(|e| WavvyError::ConfigError(e.to_string()))?;
-        let response = runtime.block_on(self.process_invoke(prompt_str))?;
-        Ok(response)
+        let responses = self.invoke_choices(prompt_str)?;
+        Ok(responses.into_iter().next().unwrap_or_default())
+    }
+
+    /// Async counterpart of `invoke_choices`, generating on a worker thread.
+    pub async fn chat_choices(self, prompt_str: String) -> Result<Vec<ChatResponse>, WavvyError> {
+        let mut stream = self.stream_invoke(prompt_str)?;
+        let mut choices = vec![];
+        while let Some(item) = stream.next().await {
+            merge_choice(&mut choices, item?);
+        }
+        Ok(choices)
     }
 
-    pub fn stream_invoke(self, prompt_str: String) -> Result<WavvyChatStream, WavvyError> {
-        let wavvy = WavvyChatStream::new(
+    pub async fn chat(self, prompt_str: String) -> Result<ChatResponse, WavvyError> {
+        let responses = self.chat_choices(prompt_str).await?;
+        Ok(responses.into_iter().next().unwrap_or_default())
+    }
+
+    pub fn beam_search(self, prompt_str: String) -> Result<Vec<BeamHypothesis>, WavvyError> {
+        BeamSearch::new(
             self.model,
             self.base_model,
             self.tokenizer,
             &self.device,
             Some(self.args),
-        );
-        let wavvy_stream = wavvy.invoke(prompt_str)?;
-        Ok(wavvy_stream)
+        )
+        .with_sampler_chain(self.sampler_chain)
+        .invoke(prompt_str)
+    }
+
+    /// Prefill and decoding run on a worker thread, the returned stream only
+    /// waits for its items.
+    pub fn stream_invoke(self, prompt_str: String) -> Result<WorkerStream, WavvyError> {
+        WorkerStream::spawn(self.into_stream(), prompt_str)
+    }
+
+    /// Blocking iterator over the streamed items, run on the calling thread.
+    pub fn iter_invoke(self, prompt_str: String) -> Result<WavvyChatStream, WavvyError> {
+        self.into_stream().invoke(prompt_str)
+    }
+}
+
+fn merge_choice(choices: &mut Vec<ChatResponse>, chunk: ChatResponse) {
+    if choices.len() <= chunk.index {
+        choices.resize_with(chunk.index + 1, ChatResponse::default);
     }
+    choices[chunk.index].merge(chunk);
 }
@@ -1,23 +1,94 @@
-use std::task::Poll;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
 use crate::prompt_template::chat_template::Model;
 
+use super::banned_strings::{BannedStrings, BannedStringsCheck};
+use super::cancellation::CancellationToken;
+use super::kv_cache::{KvCacheConfig, KvCacheDType, KvCacheUsage};
+use super::latency::{LatencyMetrics, LatencyTracker};
+use super::logit_processor::{LogitProcessor, SamplerChain, TokenHistory};
+use super::prompt_lookup::PromptLookup;
+use super::quantized_qwen2::ModelWeights as Qwen2;
+use super::speculative::{sampling_probs, DraftModel, SpeculativeSampler, SpeculativeStats};
 use super::token_output::TokenOutput;
-use candle_core::{Device, Tensor};
+use candle_core::{Device, Tensor, D};
 use candle_transformers::generation::{LogitsProcessor, Sampling};
-use candle_transformers::models::quantized_qwen2::ModelWeights as Qwen2;
-use futures::Stream;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use thiserror::Error;
 use tokenizers::{Encoding, Tokenizer};
+use tracing::Span;
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
 
 #[derive(Error, Debug)]
 pub enum WavvyError {
     #[error("Config error, {0}")]
     ConfigError(String),
+    #[error("Invalid config, {context}: {source}")]
+    InvalidConfig {
+        context: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error("Invalid argument, {0}")]
+    InvalidArgument(String),
+    #[error("Model load error, {path}: {source}")]
+    ModelLoadError {
+        path: String,
+        #[source]
+        source: candle_core::Error,
+    },
     #[error("Tokenizer error, {0}")]
-    TokenizerError(String),
+    TokenizerError(#[source] tokenizers::Error),
+    #[error("Template error, {0}")]
+    TemplateError(#[source] mustache::Error),
     #[error("Prompt error, {0}")]
     PromptError(String),
+    #[error(
+        "Context overflow, {prompt_tokens} prompt tokens exceed the {max_prompt_tokens} left by the context"
+    )]
+    ContextOverflow {
+        prompt_tokens: usize,
+        max_prompt_tokens: usize,
+    },
+    #[error("Forward error, {0}")]
+    ForwardError(#[source] candle_core::Error),
+    #[error("Sampling error, {0}")]
+    SamplingError(#[source] candle_core::Error),
+    #[error("Worker error, {0}")]
+    WorkerError(#[source] std::io::Error),
+    #[error("Worker panicked, {0}")]
+    WorkerPanicked(String),
+    #[error("Generation cancelled")]
+    Cancelled,
+}
+
+impl WavvyError {
+    /// Whether the same request may succeed when sent again. Only failures of
+    /// the device or to start the worker thread are transient, everything else,
+    /// a panic of the worker included, comes from the request, the model files or
+    /// the caller.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::ForwardError(e) => is_device_error(e),
+            Self::WorkerError(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// CUDA and Metal errors, out of memory in particular, as opposed to shape or
+/// dtype errors that would fail the same way again.
+fn is_device_error(error: &candle_core::Error) -> bool {
+    match error {
+        candle_core::Error::Cuda(_) | candle_core::Error::Metal(_) => true,
+        candle_core::Error::Context { inner, .. }
+        | candle_core::Error::WithPath { inner, .. }
+        | candle_core::Error::WithBacktrace { inner, .. } => is_device_error(inner),
+        _ => false,
+    }
 }
 
 pub struct WavvyChatStream {
@@ -29,31 +100,129 @@ pub struct WavvyChatStream {
     eos_token: u32,
     index: usize,
     next_token: u32,
+    next_log_prob: f32,
+    log_probs: Vec<f32>,
     tokens: Encoding,
     token_ids: Vec<u32>,
     logits_processor: LogitsProcessor,
+    sampler_chain: SamplerChain,
+    banned_strings: Option<BannedStrings>,
+    draft_model: Option<DraftModel>,
+    prompt_lookup: Option<PromptLookup>,
+    speculative_sampler: Option<SpeculativeSampler>,
+    pending_tokens: VecDeque<(u32, f32)>,
+    discarded_tokens: usize,
+    cancellation_token: Option<CancellationToken>,
+    prefill_progress: Option<PrefillProgressCallback>,
+    started_at: Option<Instant>,
+    latency: LatencyTracker,
+    request_id: String,
+    span: Span,
+    finish_reason: Option<FinishReason>,
+    choices: Vec<WavvyChatStream>,
+    choice_index: usize,
+    next_choice: usize,
+    ranked_responses: Option<VecDeque<ChatResponse>>,
     is_prompt_initialized: bool,
+    is_finished: bool,
     pub args: WavvyArgs,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    Stop,
+    Length,
+    Cancelled,
+    Timeout,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PrefillProgress {
+    pub processed: usize,
+    pub total: usize,
+}
+
+pub type PrefillProgressCallback = Box<dyn FnMut(PrefillProgress) + Send>;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ChatResponse {
+    pub index: usize,
     pub content: String,
     pub prompt_tokens: usize,
     pub completion_tokens: usize,
     pub total_tokens: usize,
+    pub log_prob: f32,
+    pub speculative: Option<SpeculativeStats>,
+    pub finish_reason: Option<FinishReason>,
+    pub kv_cache: KvCacheUsage,
+    /// Set on the last item of a choice.
+    pub latency: Option<LatencyMetrics>,
+}
+
+impl ChatResponse {
+    pub fn mean_log_prob(&self) -> f32 {
+        self.log_prob / self.completion_tokens.max(1) as f32
+    }
+
+    /// Appends a streamed chunk of the same choice, keeping its latest usage.
+    pub fn merge(&mut self, chunk: ChatResponse) {
+        self.index = chunk.index;
+        self.content.push_str(&chunk.content);
+        self.prompt_tokens = chunk.prompt_tokens;
+        self.completion_tokens = chunk.completion_tokens;
+        self.total_tokens = chunk.total_tokens;
+        self.log_prob = chunk.log_prob;
+        self.speculative = chunk.speculative;
+        self.finish_reason = chunk.finish_reason;
+        self.kv_cache = chunk.kv_cache;
+        self.latency = chunk.latency;
+    }
 }
 
-#[derive(Clone, Debug)]
+/// Missing fields take their default when deserialized, so presets only list
+/// what they change.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct WavvyArgs {
     pub sample_len: usize,
     pub temperature: f64,
     pub top_p: Option<f64>,
     pub top_k: Option<usize>,
     pub seed: u64,
+    /// Prefill one token per forward instead of the whole prompt at once. Off
+    /// by default like the CLI flag, library callers used to get it on.
     pub split_prompt: bool,
+    /// Prompt tokens per prefill forward, `split_prompt` picks 1 or the whole
+    /// prompt when unset.
+    pub prefill_chunk_size: Option<usize>,
     pub repeat_penalty: f32,
     pub repeat_last_n: usize,
+    pub dry_multiplier: f32,
+    pub dry_base: f32,
+    pub dry_allowed_length: usize,
+    pub dry_penalty_last_n: usize,
+    pub dry_sequence_breakers: Vec<String>,
+    #[serde(with = "token_id_keys")]
+    pub logit_bias: HashMap<u32, f32>,
+    pub banned_strings: Vec<String>,
+    pub num_draft_tokens: usize,
+    pub prompt_lookup_num_tokens: usize,
+    pub prompt_lookup_max_ngram: usize,
+    pub num_beams: usize,
+    pub length_penalty: f32,
+    pub early_stopping: bool,
+    pub n: usize,
+    pub best_of: Option<usize>,
+    /// In seconds when serialized.
+    #[serde(with = "duration_secs")]
+    pub max_duration: Option<Duration>,
+    pub channel_capacity: usize,
+    pub context_shift: bool,
+    pub sink_tokens: usize,
+    pub kv_cache_dtype: KvCacheDType,
+    pub kv_cache_capacity: Option<usize>,
 }
 
 impl Default for WavvyArgs {
@@ -64,13 +233,87 @@ impl Default for WavvyArgs {
             top_p: None,
             top_k: None,
             seed: 299792458,
-            split_prompt: true,
+            split_prompt: false,
+            prefill_chunk_size: None,
             repeat_penalty: 1.1,
             repeat_last_n: 65,
+            dry_multiplier: 0.,
+            dry_base: 1.75,
+            dry_allowed_length: 2,
+            dry_penalty_last_n: 0,
+            dry_sequence_breakers: vec![
+                String::from("\n"),
+                String::from(":"),
+                String::from("\""),
+                String::from("*"),
+            ],
+            logit_bias: HashMap::new(),
+            banned_strings: vec![],
+            num_draft_tokens: 5,
+            prompt_lookup_num_tokens: 0,
+            prompt_lookup_max_ngram: 3,
+            num_beams: 1,
+            length_penalty: 1.,
+            early_stopping: false,
+            n: 1,
+            best_of: None,
+            max_duration: None,
+            channel_capacity: 16,
+            context_shift: false,
+            sink_tokens: 4,
+            kv_cache_dtype: KvCacheDType::F32,
+            kv_cache_capacity: None,
         }
     }
 }
 
+/// Token ids as string keys, which TOML tables need.
+mod token_id_keys {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        map: &HashMap<u32, f32>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(map.iter().map(|(token, bias)| (token.to_string(), bias)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<HashMap<u32, f32>, D::Error> {
+        HashMap::<String, f32>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(token, bias)| {
+                token
+                    .parse::<u32>()
+                    .map(|token| (token, bias))
+                    .map_err(serde::de::Error::custom)
+            })
+            .collect()
+    }
+}
+
+mod duration_secs {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        duration: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        duration
+            .map(|duration| duration.as_secs_f64())
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Option::<f64>::deserialize(deserializer)?
+            .map(|secs| Duration::try_from_secs_f64(secs).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
 impl WavvyChatStream {
     pub fn new(
         model: Model,
@@ -89,10 +332,31 @@ impl WavvyChatStream {
             eos_token: 0,
             index: 0,
             next_token: 0,
+            next_log_prob: 0.,
+            log_probs: vec![],
             tokens: Encoding::default(),
             token_ids: vec![],
             logits_processor: LogitsProcessor::from_sampling(default_args.seed, Sampling::ArgMax),
+            sampler_chain: SamplerChain::new(),
+            banned_strings: None,
+            draft_model: None,
+            prompt_lookup: None,
+            speculative_sampler: None,
+            pending_tokens: VecDeque::new(),
+            discarded_tokens: 0,
+            cancellation_token: None,
+            prefill_progress: None,
+            started_at: None,
+            latency: LatencyTracker::new(),
+            request_id: format!("req-{}", NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed)),
+            span: Span::none(),
+            finish_reason: None,
+            choices: vec![],
+            choice_index: 0,
+            next_choice: 0,
+            ranked_responses: None,
             is_prompt_initialized: false,
+            is_finished: false,
             args: args.clone().unwrap_or(default_args),
         }
     }
@@ -112,41 +376,144 @@ impl WavvyChatStream {
         LogitsProcessor::from_sampling(self.args.seed, sampling)
     }
 
-    fn prompt_next_token(&mut self) -> Result<u32, WavvyError> {
-        let next_token = if !self.args.split_prompt {
-            let input = Tensor::new(self.token_ids.clone(), &self.device)
-                .map_err(|e| WavvyError::PromptError(e.to_string()))?
+    pub fn with_logit_processor(mut self, processor: impl LogitProcessor + 'static) -> Self {
+        self.sampler_chain.push(processor);
+        self
+    }
+
+    pub fn with_sampler_chain(mut self, sampler_chain: SamplerChain) -> Self {
+        self.sampler_chain = sampler_chain;
+        self
+    }
+
+    pub fn with_draft_model(mut self, draft_model: Qwen2) -> Self {
+        self.draft_model = Some(DraftModel::new(draft_model));
+        self
+    }
+
+    /// Ends the stream with a `Cancelled` item carrying the usage so far once the
+    /// token is cancelled. A cancel that lands during prefill makes `invoke` fail
+    /// with `WavvyError::Cancelled` instead, there is no usage to report yet.
+    pub fn with_cancellation_token(mut self, cancellation_token: CancellationToken) -> Self {
+        self.cancellation_token = Some(cancellation_token);
+        self
+    }
+
+    pub fn with_request_id(mut self, request_id: impl Into<String>) -> Self {
+        self.request_id = request_id.into();
+        self
+    }
+
+    /// Called after every prefill chunk.
+    pub fn with_prefill_progress(
+        mut self,
+        prefill_progress: impl FnMut(PrefillProgress) + Send + 'static,
+    ) -> Self {
+        self.prefill_progress = Some(Box::new(prefill_progress));
+        self
+    }
+
+    /// Token stopping this stream, created on first use when none was given.
+    pub fn cancellation_token(&mut self) -> CancellationToken {
+        self.cancellation_token
+            .get_or_insert_with(CancellationToken::new)
+            .clone()
+    }
+
+    fn init_sampling(&mut self) {
+        self.logits_processor = self.init_logits_processor();
+        if self.draft_model.is_none() && self.args.prompt_lookup_num_tokens > 0 {
+            self.prompt_lookup = Some(PromptLookup::new(
+                self.args.prompt_lookup_max_ngram,
+                self.args.prompt_lookup_num_tokens,
+            ));
+        }
+        if self.draft_model.is_some() || self.prompt_lookup.is_some() {
+            self.speculative_sampler = Some(SpeculativeSampler::new(self.args.seed));
+        }
+        if !self.args.banned_strings.is_empty() {
+            self.banned_strings = Some(BannedStrings::new(&self.args.banned_strings, &self.tos));
+        }
+    }
+
+    /// Starts choice `choice_index` of a multi-completion request from the
+    /// prefilled KV cache, with its own seed.
+    fn fork(&self, choice_index: usize, prompt_logits: &Tensor) -> Result<Self, WavvyError> {
+        let mut args = self.args.clone();
+        args.seed = args.seed.wrapping_add(choice_index as u64);
+        let mut choice = Self::new(
+            self.model.clone(),
+            self.base_model.clone(),
+            self.tos.tokenizer().clone(),
+            &self.device,
+            Some(args),
+        );
+        choice.choice_index = choice_index;
+        choice.eos_token = self.eos_token;
+        choice.tokens = self.tokens.clone();
+        choice.token_ids = self.token_ids.clone();
+        choice.sampler_chain = self.sampler_chain.clone();
+        choice.draft_model = self.draft_model.clone();
+        choice.cancellation_token = self.cancellation_token.clone();
+        choice.started_at = self.started_at;
+        choice.latency = self.latency.clone();
+        choice.request_id = self.request_id.clone();
+        choice.span = tracing::info_span!(parent: &self.span, "choice", index = choice_index);
+        choice.init_sampling();
+        (choice.next_token, choice.next_log_prob) =
+            choice.sample_first_token(prompt_logits.clone())?;
+        Ok(choice)
+    }
+
+    #[tracing::instrument(skip_all, fields(tokens = self.token_ids.len()))]
+    fn prefill(&mut self) -> Result<Tensor, WavvyError> {
+        let total = self.token_ids.len();
+        let chunk_size = match self.args.prefill_chunk_size {
+            Some(chunk_size) => chunk_size.max(1),
+            None if self.args.split_prompt => 1,
+            None => total.max(1),
+        };
+
+        let mut logits = None;
+        for (chunk_index, chunk) in self.token_ids.chunks(chunk_size).enumerate() {
+            if self.is_cancelled() {
+                return Err(WavvyError::Cancelled);
+            }
+            let pos = chunk_index * chunk_size;
+            let input = Tensor::new(chunk, &self.device)
+                .map_err(WavvyError::ForwardError)?
                 .unsqueeze(0)
-                .map_err(|e| WavvyError::PromptError(e.to_string()))?;
-            let logits = self.base_model.forward(&input, 0).unwrap();
-            let logits = logits
-                .squeeze(0)
-                .map_err(|e| WavvyError::PromptError(e.to_string()))?;
-            self.logits_processor
-                .sample(&logits)
-                .map_err(|e| WavvyError::PromptError(e.to_string()))?
-        } else {
-            let mut next_token = 0;
-            for (pos, token) in self.token_ids.iter().enumerate() {
-                let input = Tensor::new(&[*token], &self.device)
-                    .map_err(|e| WavvyError::PromptError(e.to_string()))?
-                    .unsqueeze(0)
-                    .map_err(|e| WavvyError::PromptError(e.to_string()))?;
-                let logits = self
-                    .base_model
+                .map_err(WavvyError::ForwardError)?;
+            logits = Some(
+                self.base_model
                     .forward(&input, pos)
-                    .map_err(|e| WavvyError::PromptError(e.to_string()))?;
-                let logits = logits
-                    .squeeze(0)
-                    .map_err(|e| WavvyError::PromptError(e.to_string()))?;
-                next_token = self
-                    .logits_processor
-                    .sample(&logits)
-                    .map_err(|e| WavvyError::PromptError(e.to_string()))?;
+                    .map_err(WavvyError::ForwardError)?,
+            );
+            tracing::debug!(processed = pos + chunk.len(), total, "prefill chunk");
+            if let Some(prefill_progress) = self.prefill_progress.as_mut() {
+                prefill_progress(PrefillProgress {
+                    processed: pos + chunk.len(),
+                    total,
+                });
             }
-            next_token
-        };
-        Ok(next_token)
+        }
+        logits
+            .ok_or_else(|| WavvyError::InvalidArgument(String::from("empty prompt")))?
+            .squeeze(0)
+            .map_err(WavvyError::ForwardError)
+    }
+
+    fn sample_first_token(&mut self, logits: Tensor) -> Result<(u32, f32), WavvyError> {
+        let logits = self.apply_logit_processors(logits, &[])?;
+        self.sample(&logits)
+    }
+
+    fn sample(&mut self, logits: &Tensor) -> Result<(u32, f32), WavvyError> {
+        let token = self
+            .logits_processor
+            .sample(logits)
+            .map_err(WavvyError::SamplingError)?;
+        Ok((token, token_log_prob(logits, token)?))
     }
 
     pub fn process_logits(
@@ -156,128 +523,578 @@ impl WavvyChatStream {
         all_tokens: Vec<u32>,
     ) -> Result<Tensor, WavvyError> {
         let input = Tensor::new(&[next_token], &self.device)
-            .map_err(|e| WavvyError::PromptError(e.to_string()))?
+            .map_err(WavvyError::ForwardError)?
             .unsqueeze(0)
-            .map_err(|e| WavvyError::PromptError(e.to_string()))?;
+            .map_err(WavvyError::ForwardError)?;
 
         let logits = self
             .base_model
-            .forward(&input, self.token_ids.len() + index)
-            .map_err(|e| WavvyError::PromptError(e.to_string()))?;
+            .forward(&input, self.token_ids.len() + index - self.discarded_tokens)
+            .map_err(WavvyError::ForwardError)?;
 
-        let logits = logits
-            .squeeze(0)
-            .map_err(|e| WavvyError::PromptError(e.to_string()))?;
+        let logits = logits.squeeze(0).map_err(WavvyError::ForwardError)?;
 
-        let logits = if self.args.repeat_penalty == 1. {
-            logits
-        } else {
-            let start_at = all_tokens.len().saturating_sub(self.args.repeat_last_n);
-            candle_transformers::utils::apply_repeat_penalty(
-                &logits,
-                self.args.repeat_penalty,
-                &all_tokens[start_at..],
+        self.apply_logit_processors(logits, &all_tokens)
+    }
+
+    fn apply_logit_processors(
+        &mut self,
+        logits: Tensor,
+        all_tokens: &[u32],
+    ) -> Result<Tensor, WavvyError> {
+        let history = TokenHistory {
+            prompt: &self.token_ids,
+            completion: all_tokens,
+        };
+        let logits = self
+            .sampler_chain
+            .process(logits, &history)
+            .map_err(WavvyError::SamplingError)?;
+
+        // Banned tokens are masked after the chain so that no stage can bring them back.
+        let banned_tokens: Vec<u32> = match &self.banned_strings {
+            None => return Ok(logits),
+            Some(banned_strings) => banned_strings
+                .banned_tokens(all_tokens.len())
+                .copied()
+                .collect(),
+        };
+        if banned_tokens.is_empty() {
+            return Ok(logits);
+        }
+
+        let device = logits.device().clone();
+        let mut logits = logits
+            .to_dtype(candle_core::DType::F32)
+            .and_then(|logits| logits.to_vec1::<f32>())
+            .map_err(WavvyError::SamplingError)?;
+        for token in banned_tokens {
+            if let Some(logit) = logits.get_mut(token as usize) {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+        let logits_len = logits.len();
+        Tensor::from_vec(logits, logits_len, &device).map_err(WavvyError::SamplingError)
+    }
+
+    fn rewind(&mut self, len: usize) -> Result<(u32, f32), WavvyError> {
+        self.all_tokens.truncate(len);
+        self.log_probs.truncate(len);
+        self.tos.truncate(len);
+        self.pending_tokens.clear();
+
+        // Running the last kept token again at its position drops everything after
+        // it from the KV cache and gives the logits for the rewound position.
+        let context_len = self.token_ids.len() + self.all_tokens.len();
+        if self.discarded_tokens > 0
+            && context_len - 1 < self.args.sink_tokens + self.discarded_tokens
+        {
+            return Err(WavvyError::InvalidArgument(String::from(
+                "cannot rewind past a context shift",
+            )));
+        }
+        let last_token = self
+            .all_tokens
+            .last()
+            .or(self.token_ids.last())
+            .copied()
+            .ok_or_else(|| WavvyError::InvalidArgument(String::from("empty prompt")))?;
+        let kept_len = context_len - 1 - self.discarded_tokens;
+        if let Some(draft_model) = self.draft_model.as_mut() {
+            draft_model
+                .rollback(kept_len)
+                .map_err(WavvyError::ForwardError)?;
+        }
+        let input = Tensor::new(&[last_token], &self.device)
+            .map_err(WavvyError::ForwardError)?
+            .unsqueeze(0)
+            .map_err(WavvyError::ForwardError)?;
+        let logits = self
+            .base_model
+            .forward(&input, kept_len)
+            .map_err(WavvyError::ForwardError)?;
+        let logits = logits.squeeze(0).map_err(WavvyError::ForwardError)?;
+        let logits = self.apply_logit_processors(logits, &self.all_tokens.clone())?;
+        self.sample(&logits)
+    }
+
+    /// Tokens whose entries are in the KV cache, the sink tokens followed by
+    /// everything after the part dropped by context shifts.
+    fn cached_context(&self) -> Vec<u32> {
+        let context = [self.token_ids.as_slice(), self.all_tokens.as_slice()].concat();
+        if self.discarded_tokens == 0 {
+            return context;
+        }
+        let sink_tokens = self.args.sink_tokens.min(context.len());
+        let resume_at = (sink_tokens + self.discarded_tokens).min(context.len());
+        [&context[..sink_tokens], &context[resume_at..]].concat()
+    }
+
+    /// Makes room for `incoming` tokens when the KV cache would outgrow the
+    /// context, dropping half of the entries after the sink tokens.
+    fn shift_context(&mut self, incoming: usize) -> Result<(), WavvyError> {
+        if !self.args.context_shift {
+            return Ok(());
+        }
+        let context_length = self.base_model.context_length();
+        let cached = self.token_ids.len() + self.all_tokens.len() - 1 - self.discarded_tokens;
+        if cached + incoming <= context_length {
+            return Ok(());
+        }
+
+        let keep = self.args.sink_tokens.min(cached);
+        let discard = ((cached - keep) / 2)
+            .max(cached + incoming - context_length)
+            .min(cached - keep);
+        self.base_model
+            .shift_kv_cache(keep, discard)
+            .map_err(WavvyError::ForwardError)?;
+        self.discarded_tokens += discard;
+        // The draft model has its own positions, it reads the shifted context again.
+        if let Some(draft_model) = self.draft_model.as_mut() {
+            draft_model.rollback(0).map_err(WavvyError::ForwardError)?;
+        }
+        Ok(())
+    }
+
+    fn speculative_step(&mut self) -> Result<VecDeque<(u32, f32)>, WavvyError> {
+        let context = self.cached_context();
+        let (Some(draft_model), Some(sampler)) =
+            (self.draft_model.as_mut(), self.speculative_sampler.as_mut())
+        else {
+            return Err(WavvyError::ConfigError(String::from(
+                "speculative decoding needs a draft model",
+            )));
+        };
+
+        let num_draft_tokens = self.args.num_draft_tokens.min(draft_budget(
+            self.base_model.context_length(),
+            context.len() - 1,
+        ));
+        let (drafts, draft_probs) = draft_model
+            .propose(
+                &context,
+                num_draft_tokens,
+                &self.args,
+                sampler,
+                &self.device,
             )
-            .map_err(|e| WavvyError::PromptError(e.to_string()))?
+            .map_err(WavvyError::ForwardError)?;
+
+        let tokens = self.verify_drafts(&drafts, Some(&draft_probs))?;
+
+        if let Some(draft_model) = self.draft_model.as_mut() {
+            draft_model
+                .rollback(context.len() + tokens.len() - 1)
+                .map_err(WavvyError::ForwardError)?;
+        }
+        Ok(tokens)
+    }
+
+    fn prompt_lookup_step(&mut self) -> Result<VecDeque<(u32, f32)>, WavvyError> {
+        let Some(prompt_lookup) = &self.prompt_lookup else {
+            return Ok(VecDeque::new());
         };
-        Ok(logits)
+        let context = [self.token_ids.as_slice(), self.all_tokens.as_slice()].concat();
+        let drafts = prompt_lookup.propose(&context);
+        if drafts.is_empty() {
+            return Ok(VecDeque::new());
+        }
+        self.verify_drafts(&drafts, None)
     }
 
-    pub fn invoke(mut self, prompt_str: String) -> Result<Self, WavvyError> {
-        if self.model == Model::R1 {
-            self.eos_token = *self
-                .tos
-                .tokenizer()
-                .get_vocab(true)
-                .get("<｜end▁of▁sentence｜>")
-                .unwrap();
-        } else if self.model == Model::W {
-            self.eos_token = *self
+    /// Runs `next_token` and the drafted tokens through the model in a single
+    /// forward and returns the accepted tokens, always followed by one token
+    /// sampled from the model itself.
+    fn verify_drafts(
+        &mut self,
+        drafts: &[u32],
+        draft_probs: Option<&[Vec<f32>]>,
+    ) -> Result<VecDeque<(u32, f32)>, WavvyError> {
+        let index_pos = self.token_ids.len() + self.all_tokens.len() - 1 - self.discarded_tokens;
+        let budget = draft_budget(self.base_model.context_length(), index_pos);
+        let drafts = &drafts[..drafts.len().min(budget)];
+        let input = Tensor::new([&[self.next_token], drafts].concat(), &self.device)
+            .map_err(WavvyError::ForwardError)?
+            .unsqueeze(0)
+            .map_err(WavvyError::ForwardError)?;
+        let logits = self
+            .base_model
+            .forward_all(&input, index_pos)
+            .map_err(WavvyError::ForwardError)?
+            .squeeze(0)
+            .map_err(WavvyError::ForwardError)?;
+
+        let mut completion = self.all_tokens.clone();
+        let mut tokens = VecDeque::new();
+        for index in 0..=drafts.len() {
+            let logits = logits.get(index).map_err(WavvyError::ForwardError)?;
+            let logits = self.apply_logit_processors(logits, &completion)?;
+            let target = sampling_probs(&logits, &self.args).map_err(WavvyError::SamplingError)?;
+            let sampler = self.speculative_sampler.as_mut().ok_or_else(|| {
+                WavvyError::ConfigError(String::from("speculative sampler is not initialized"))
+            })?;
+
+            let Some(&draft) = drafts.get(index) else {
+                let token = sampler.sample(&target).map_err(WavvyError::SamplingError)?;
+                tokens.push_back((token, token_log_prob(&logits, token)?));
+                break;
+            };
+            let draft_probs = draft_probs.map(|probs| probs[index].as_slice());
+            let rejected = sampler
+                .verify(draft, &target, draft_probs)
+                .map_err(WavvyError::SamplingError)?;
+            match rejected {
+                None => {
+                    tokens.push_back((draft, token_log_prob(&logits, draft)?));
+                    completion.push(draft);
+                }
+                Some(token) => {
+                    tokens.push_back((token, token_log_prob(&logits, token)?));
+                    break;
+                }
+            }
+        }
+
+        if let Some(sampler) = self.speculative_sampler.as_mut() {
+            sampler.stats.draft_tokens += drafts.len();
+            sampler.stats.accepted_tokens += tokens.len() - 1;
+        }
+        self.base_model
+            .truncate_kv_cache(index_pos + tokens.len())
+            .map_err(WavvyError::ForwardError)?;
+        Ok(tokens)
+    }
+
+    fn next_text(&mut self) -> Result<Option<String>, WavvyError> {
+        if self.banned_strings.is_none() {
+            return self
                 .tos
-                .tokenizer()
-                .get_vocab(true)
-                .get("<|im_end|>")
-                .unwrap();
+                .next_token(self.next_token)
+                .map_err(WavvyError::TokenizerError);
+        }
+
+        self.tos.push_token(self.next_token);
+        while let Some(banned_strings) = self.banned_strings.as_mut() {
+            let check = banned_strings
+                .check(&self.tos)
+                .map_err(WavvyError::TokenizerError)?;
+            let position = match check {
+                BannedStringsCheck::Release(text) if text.is_empty() => return Ok(None),
+                BannedStringsCheck::Release(text) => return Ok(Some(text)),
+                BannedStringsCheck::Rewind(position) => position,
+            };
+            banned_strings.ban(position, self.all_tokens[position]);
+            (self.next_token, self.next_log_prob) = self.rewind(position)?;
+            self.all_tokens.push(self.next_token);
+            self.log_probs.push(self.next_log_prob);
+            self.tos.push_token(self.next_token);
         }
+        Ok(None)
+    }
+
+    pub fn invoke(mut self, prompt_str: String) -> Result<Self, WavvyError> {
+        self.latency.invoked();
+        self.args.validate()?;
+        self.span = tracing::info_span!(
+            "generation",
+            request_id = %self.request_id,
+            prompt_tokens = tracing::field::Empty,
+        );
+        let span = self.span.clone();
+        let _entered = span.enter();
+        self.eos_token = self.tos.get_token(self.model.eos_token()).ok_or_else(|| {
+            WavvyError::ConfigError(format!("tokenizer has no {} token", self.model.eos_token()))
+        })?;
 
-        self.tokens = self
-            .tos
-            .tokenizer()
-            .encode(prompt_str, true)
-            .map_err(|e| WavvyError::TokenizerError(e.to_string()))?;
+        let tokenization_start = Instant::now();
+        self.tokens = tracing::info_span!("tokenize")
+            .in_scope(|| self.tos.tokenizer().encode(prompt_str, true))
+            .map_err(WavvyError::TokenizerError)?;
+        self.latency.tokenized(tokenization_start.elapsed());
 
         self.token_ids = self.tokens.get_ids().to_vec();
+        self.span.record("prompt_tokens", self.token_ids.len());
 
-        self.logits_processor = self.init_logits_processor();
-        self.next_token = self.prompt_next_token()?;
+        // Room is kept for `sample_len` tokens, past the context RoPE has no positions.
+        // With context shifting only the prompt has to fit, along with its first token.
+        let context_length = self.base_model.context_length();
+        // A shift has to leave room for at least one token after the sink.
+        if self.args.context_shift && self.args.sink_tokens >= context_length.saturating_sub(1) {
+            return Err(WavvyError::InvalidArgument(format!(
+                "sink_tokens must be below {}, the context length minus one",
+                context_length.saturating_sub(1)
+            )));
+        }
+        let max_prompt_tokens = if self.args.context_shift {
+            context_length.saturating_sub(1)
+        } else {
+            context_length.saturating_sub(self.args.sample_len)
+        };
+        if self.token_ids.len() > max_prompt_tokens {
+            return Err(WavvyError::ContextOverflow {
+                prompt_tokens: self.token_ids.len(),
+                max_prompt_tokens,
+            });
+        }
+
+        let best_of = self.args.best_of.unwrap_or(self.args.n);
+
+        if self.is_cancelled() {
+            return Err(WavvyError::Cancelled);
+        }
+        self.base_model
+            .set_kv_cache_config(KvCacheConfig {
+                dtype: self.args.kv_cache_dtype,
+                capacity: self.args.kv_cache_capacity,
+            })
+            .map_err(|e| WavvyError::InvalidConfig {
+                context: String::from("KV cache"),
+                source: e.into(),
+            })?;
+        self.started_at = Some(Instant::now());
+        let custom_processors = std::mem::take(&mut self.sampler_chain);
+        self.sampler_chain = SamplerChain::from_args(&self.args, self.tos.tokenizer())?;
+        self.sampler_chain.append(custom_processors);
+        self.init_sampling();
+
+        let prefill_start = Instant::now();
+        let logits = self.prefill()?;
+        self.latency.prefilled(prefill_start.elapsed());
+        if best_of > 1 {
+            self.choices = (0..best_of)
+                .map(|choice_index| self.fork(choice_index, &logits))
+                .collect::<Result<_, _>>()?;
+            // The choices hold the prefilled cache from here on.
+            self.base_model.clear_kv_cache();
+        } else {
+            (self.next_token, self.next_log_prob) = self.sample_first_token(logits)?;
+        }
 
         Ok(self)
     }
-}
 
-impl Stream for WavvyChatStream {
-    type Item = Result<ChatResponse, WavvyError>;
+    fn response(&self, content: String) -> ChatResponse {
+        let prompt_tokens = self.token_ids.len();
+        let completion_tokens = self.tos.total_tokens();
+        ChatResponse {
+            index: self.choice_index,
+            content,
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+            log_prob: self.log_probs.iter().sum(),
+            speculative: self
+                .speculative_sampler
+                .as_ref()
+                .map(|sampler| sampler.stats.clone()),
+            finish_reason: self.finish_reason,
+            kv_cache: self.kv_cache_usage(),
+            latency: self.finish_reason.map(|_| self.latency.metrics()),
+        }
+    }
+
+    pub fn kv_cache_usage(&self) -> KvCacheUsage {
+        self.base_model.kv_cache_usage().unwrap_or_default()
+    }
 
-    fn poll_next(
-        mut self: std::pin::Pin<&mut Self>,
-        _cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Option<Self::Item>> {
-        let this = self.as_mut().get_mut();
+    fn is_cancelled(&self) -> bool {
+        self.cancellation_token
+            .as_ref()
+            .is_some_and(|token| token.is_cancelled())
+    }
 
-        if this.index == this.args.sample_len.saturating_sub(1) {
-            return Poll::Ready(None);
+    fn check_finished(&self) -> Option<FinishReason> {
+        if self.next_token == self.eos_token {
+            return Some(FinishReason::Stop);
+        }
+        if self.index == self.args.sample_len.saturating_sub(1) {
+            return Some(FinishReason::Length);
         }
+        if self.is_cancelled() {
+            return Some(FinishReason::Cancelled);
+        }
+        match (self.started_at, self.args.max_duration) {
+            (Some(started_at), Some(max_duration)) if started_at.elapsed() >= max_duration => {
+                Some(FinishReason::Timeout)
+            }
+            _ => None,
+        }
+    }
 
-        if this.next_token == this.eos_token {
-            return Poll::Ready(None);
+    fn step(&mut self) -> Result<Option<ChatResponse>, WavvyError> {
+        if self.is_finished {
+            return Ok(None);
         }
+        let span = self.span.clone();
+        let _entered = span.enter();
 
-        if !this.is_prompt_initialized {
-            if let Some(text) = this
-                .tos
-                .next_token(this.next_token)
-                .map_err(|e| WavvyError::PromptError(e.to_string()))?
-            {
-                this.is_prompt_initialized = true;
-                this.all_tokens.push(this.next_token);
-                let prompt_tokens = this.token_ids.len();
-                let completion_tokens = this.tos.total_tokens();
-                let text = if this.model == Model::W {
+        // The last item carries the finish reason and the usage so far.
+        if let Some(finish_reason) = self.check_finished() {
+            self.is_finished = true;
+            self.finish_reason = Some(finish_reason);
+            tracing::info!(
+                request_id = %self.request_id,
+                choice = self.choice_index,
+                prompt_tokens = self.token_ids.len(),
+                completion_tokens = self.tos.total_tokens(),
+                finish_reason = ?finish_reason,
+                "generation finished"
+            );
+            let rest = match self.banned_strings.as_mut() {
+                None => String::new(),
+                Some(banned_strings) => banned_strings
+                    .flush(&self.tos)
+                    .map_err(WavvyError::TokenizerError)?,
+            };
+            return Ok(Some(self.response(rest)));
+        }
+
+        if !self.is_prompt_initialized {
+            self.is_prompt_initialized = true;
+            self.all_tokens.push(self.next_token);
+            self.log_probs.push(self.next_log_prob);
+            self.latency.token();
+            if let Some(text) = self.next_text()? {
+                let text = if self.model == Model::W {
                     text
                 } else {
                     String::from("")
                 };
-                return Poll::Ready(Some(Ok(ChatResponse {
-                    content: text,
-                    prompt_tokens,
-                    completion_tokens,
-                    total_tokens: prompt_tokens + completion_tokens,
-                })));
+                return Ok(Some(self.response(text)));
             }
         }
 
-        let logits = this.process_logits(this.next_token, this.index, this.all_tokens.clone())?;
+        let _decode_step = tracing::debug_span!("decode_step", index = self.index).entered();
+        if self.pending_tokens.is_empty() {
+            let drafted = if self.draft_model.is_some() {
+                self.args.num_draft_tokens
+            } else {
+                self.prompt_lookup
+                    .as_ref()
+                    .map_or(0, |_| self.args.prompt_lookup_num_tokens)
+            };
+            self.shift_context(1 + drafted)?;
+            self.pending_tokens = if self.draft_model.is_some() {
+                self.speculative_step()?
+            } else {
+                self.prompt_lookup_step()?
+            };
+        }
+        if self.pending_tokens.is_empty() {
+            let logits =
+                self.process_logits(self.next_token, self.index, self.all_tokens.clone())?;
+            let next_token = self.sample(&logits)?;
+            self.pending_tokens.push_back(next_token);
+        }
 
-        this.next_token = this
-            .logits_processor
-            .sample(&logits)
-            .map_err(|e| WavvyError::PromptError(e.to_string()))?;
+        (self.next_token, self.next_log_prob) =
+            self.pending_tokens.pop_front().ok_or_else(|| {
+                WavvyError::SamplingError(candle_core::Error::Msg(String::from(
+                    "no token was sampled",
+                )))
+            })?;
 
-        this.all_tokens.push(this.next_token);
-        let text = this
-            .tos
-            .next_token(this.next_token)
-            .map_err(|e| WavvyError::PromptError(e.to_string()))?;
+        self.all_tokens.push(self.next_token);
+        self.log_probs.push(self.next_log_prob);
+        self.latency.token();
+        let text = self.next_text()?;
 
-        this.index += 1;
+        self.index = self.all_tokens.len() - 1;
 
-        let prompt_tokens = this.token_ids.len();
-        let completion_tokens = this.tos.total_tokens();
-        return Poll::Ready(Some(Ok(ChatResponse {
-            content: text.unwrap_or_default(),
-            prompt_tokens,
-            completion_tokens,
-            total_tokens: prompt_tokens + completion_tokens,
-        })));
+        Ok(Some(self.response(text.unwrap_or_default())))
+    }
+
+    /// Interleaves the choices when all of them are returned.
+    fn next_choice_response(&mut self) -> Result<Option<ChatResponse>, WavvyError> {
+        for _ in 0..self.choices.len() {
+            let choice = self.next_choice;
+            self.next_choice = (self.next_choice + 1) % self.choices.len();
+            if let Some(response) = self.choices[choice].step()? {
+                return Ok(Some(response));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Runs every choice to the end and keeps the `n` with the highest mean
+    /// log-probability per token, each returned as a single response.
+    fn rank_choices(&mut self) -> Result<VecDeque<ChatResponse>, WavvyError> {
+        let mut responses = Vec::with_capacity(self.choices.len());
+        for choice in self.choices.iter_mut() {
+            let mut content = String::new();
+            while let Some(response) = choice.step()? {
+                content.push_str(&response.content);
+            }
+            responses.push(choice.response(content));
+        }
+        responses.sort_by(|a, b| b.mean_log_prob().total_cmp(&a.mean_log_prob()));
+        responses.truncate(self.args.n);
+        for (index, response) in responses.iter_mut().enumerate() {
+            response.index = index;
+        }
+        Ok(responses.into())
+    }
+}
+
+/// Blocking use of the stream, each call runs the model until the next item.
+impl Iterator for WavvyChatStream {
+    type Item = Result<ChatResponse, WavvyError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.choices.is_empty() {
+            return self.step().transpose();
+        }
+        if self.choices.len() == self.args.n {
+            return self.next_choice_response().transpose();
+        }
+        if self.ranked_responses.is_none() {
+            match self.rank_choices() {
+                Ok(responses) => self.ranked_responses = Some(responses),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        self.ranked_responses
+            .as_mut()
+            .and_then(|responses| responses.pop_front())
+            .map(Ok)
+    }
+}
+
+/// Drafts that still have a position in the context when verified along with
+/// the token at `index_pos`.
+fn draft_budget(context_length: usize, index_pos: usize) -> usize {
+    context_length.saturating_sub(index_pos + 1)
+}
+
+fn token_log_prob(logits: &Tensor, token: u32) -> Result<f32, WavvyError> {
+    candle_nn::ops::log_softmax(logits, D::Minus1)
+        .and_then(|log_probs| log_probs.get(token as usize))
+        .and_then(|log_prob| log_prob.to_dtype(candle_core::DType::F32))
+        .and_then(|log_prob| log_prob.to_scalar::<f32>())
+        .map_err(WavvyError::SamplingError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drafts_stop_at_the_end_of_the_context() {
+        assert_eq!(draft_budget(8, 0), 7);
+        assert_eq!(draft_budget(8, 6), 1);
+        assert_eq!(draft_budget(8, 7), 0);
+        assert_eq!(draft_budget(8, 9), 0);
+    }
+
+    #[test]
+    fn only_device_and_spawn_errors_are_retryable() {
+        let spawn_error = WavvyError::WorkerError(std::io::Error::other("no threads left"));
+        assert!(spawn_error.is_retryable());
+        assert!(!WavvyError::WorkerPanicked(String::from("index out of bounds")).is_retryable());
+        let shape_error = candle_core::Error::Msg(String::from("shape mismatch"));
+        assert!(!WavvyError::ForwardError(shape_error).is_retryable());
+        let cuda_error = candle_core::Error::Cuda("out of memory".into());
+        assert!(WavvyError::ForwardError(cuda_error.context("forward")).is_retryable());
     }
 }
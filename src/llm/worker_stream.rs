@@ -0,0 +1,83 @@
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::Stream;
+use tokio::sync::mpsc;
+
+use super::cancellation::CancellationToken;
+use super::wavvy_chat_stream::{ChatResponse, WavvyChatStream, WavvyError};
+
+/// Generation running on its own thread. Items are sent through a bounded
+/// channel, so the worker waits for the consumer instead of running ahead.
+/// Dropping the stream cancels the generation, and a panic of the worker ends
+/// the stream with a `WorkerPanicked` error.
+pub struct WorkerStream {
+    receiver: mpsc::Receiver<Result<ChatResponse, WavvyError>>,
+    cancellation_token: CancellationToken,
+}
+
+impl WorkerStream {
+    pub fn spawn(mut wavvy: WavvyChatStream, prompt_str: String) -> Result<Self, WavvyError> {
+        let (sender, receiver) = mpsc::channel(wavvy.args.channel_capacity.max(1));
+        let cancellation_token = wavvy.cancellation_token();
+
+        std::thread::Builder::new()
+            .name(String::from("wavvy-generation"))
+            .spawn(move || {
+                let generation = panic::catch_unwind(AssertUnwindSafe(|| {
+                    let wavvy = match wavvy.invoke(prompt_str) {
+                        Ok(wavvy) => wavvy,
+                        Err(e) => {
+                            let _ = sender.blocking_send(Err(e));
+                            return;
+                        }
+                    };
+                    for item in wavvy {
+                        if sender.blocking_send(item).is_err() {
+                            break;
+                        }
+                    }
+                }));
+                if let Err(payload) = generation {
+                    let message = panic_message(&payload).to_string();
+                    let _ = sender.blocking_send(Err(WavvyError::WorkerPanicked(message)));
+                }
+            })
+            .map_err(WavvyError::WorkerError)?;
+
+        Ok(Self {
+            receiver,
+            cancellation_token,
+        })
+    }
+
+    pub fn cancel(&self) {
+        self.cancellation_token.cancel();
+    }
+}
+
+impl Stream for WorkerStream {
+    type Item = Result<ChatResponse, WavvyError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+fn panic_message(payload: &Box<dyn Any + Send>) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+impl Drop for WorkerStream {
+    fn drop(&mut self) {
+        self.cancellation_token.cancel();
+    }
+}
@@ -1,22 +1,49 @@
 use mustache::Data;
+use serde::{Deserialize, Serialize};
+use tokenizers::Tokenizer;
 
 use super::message::Message;
+use super::role::Role;
+use super::truncation::TruncationStrategy;
+use crate::llm::wavvy_chat_stream::WavvyError;
 
-#[derive(Debug, PartialEq)]
+const TRUNCATION_MARKER: &str = "…";
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Model {
+    #[default]
     W,
     R1,
 }
 
-#[derive(Debug)]
+/// Prompt tokens of a conversation, split between the content of each message
+/// and what the template adds around them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TokenBreakdown {
+    pub messages: Vec<usize>,
+    pub template_overhead: usize,
+    pub total: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct ChatTemplate {
     pub messages: Vec<Message>,
     pub model: Model,
 }
 
+impl Model {
+    pub fn eos_token(&self) -> &'static str {
+        match self {
+            Model::W => "<|im_end|>",
+            Model::R1 => "<｜end▁of▁sentence｜>",
+        }
+    }
+}
+
 impl ChatTemplate {
     pub fn new(model: Model, messages: Vec<Message>) -> Self {
-        return Self { messages, model };
+        Self { messages, model }
     }
 
     pub fn format(&self) -> String {
@@ -25,8 +52,7 @@ impl ChatTemplate {
             if self.model == Model::W {
                 let p_msg = format!(
                     "<|im_start|>{}\n{}<|im_end|>",
-                    message.to_string(),
-                    message.content
+                    message.role, message.content
                 );
                 msg.push_str(p_msg.as_str());
             } else if self.model == Model::R1 {
@@ -39,7 +65,7 @@ impl ChatTemplate {
                 let p_msg = format!("<｜{}｜>{}", cap_role, message.content);
                 msg.push_str(p_msg.as_str());
             }
-            msg.push_str("\n");
+            msg.push('\n');
         }
         if self.model == Model::W {
             msg.push_str("<|im_start|>assistant\n");
@@ -49,14 +75,283 @@ impl ChatTemplate {
         msg
     }
 
-    pub fn format_with_params(&self, data: &Data) -> String {
+    pub fn format_with_params(&self, data: &Data) -> Result<String, WavvyError> {
         let text_msg = self.format();
 
         let mut bytes = vec![];
 
-        let template = mustache::compile_str(&text_msg).unwrap();
-        template.render_data(&mut bytes, data).unwrap();
+        let template = mustache::compile_str(&text_msg).map_err(WavvyError::TemplateError)?;
+        template
+            .render_data(&mut bytes, data)
+            .map_err(WavvyError::TemplateError)?;
+
+        String::from_utf8(bytes).map_err(|_| WavvyError::TemplateError(mustache::Error::InvalidStr))
+    }
+
+    pub fn count_tokens(&self, tokenizer: &Tokenizer) -> Result<usize, WavvyError> {
+        let encoding = tokenizer
+            .encode(self.format(), true)
+            .map_err(WavvyError::TokenizerError)?;
+        Ok(encoding.len())
+    }
+
+    pub fn token_breakdown(&self, tokenizer: &Tokenizer) -> Result<TokenBreakdown, WavvyError> {
+        let total = self.count_tokens(tokenizer)?;
+        let messages = self
+            .messages
+            .iter()
+            .map(|message| {
+                tokenizer
+                    .encode(message.content.as_str(), false)
+                    .map(|encoding| encoding.len())
+                    .map_err(WavvyError::TokenizerError)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(TokenBreakdown {
+            template_overhead: total.saturating_sub(messages.iter().sum()),
+            messages,
+            total,
+        })
+    }
+
+    /// Shortens the conversation until its prompt takes at most `max_tokens`.
+    pub fn truncate(
+        &mut self,
+        tokenizer: &Tokenizer,
+        max_tokens: usize,
+        strategy: &TruncationStrategy,
+    ) -> Result<(), WavvyError> {
+        if let TruncationStrategy::KeepSystemAndLast(n) = strategy {
+            self.keep_system_and_last(*n);
+        }
+        let mut previous_tokens = None;
+        loop {
+            let prompt_tokens = self.count_tokens(tokenizer)?;
+            if prompt_tokens <= max_tokens {
+                return Ok(());
+            }
+            // A pass that did not shrink the prompt would be repeated forever.
+            let stalled = previous_tokens.is_some_and(|previous| prompt_tokens >= previous);
+            let truncated = !stalled
+                && match strategy {
+                    TruncationStrategy::DropOldest => self.drop_oldest(),
+                    TruncationStrategy::KeepSystemAndLast(_) => false,
+                    TruncationStrategy::TruncateMiddle => {
+                        self.truncate_middle(tokenizer, prompt_tokens - max_tokens)?
+                    }
+                };
+            if !truncated {
+                return Err(WavvyError::ContextOverflow {
+                    prompt_tokens,
+                    max_prompt_tokens: max_tokens,
+                });
+            }
+            previous_tokens = Some(prompt_tokens);
+        }
+    }
+
+    fn keep_system_and_last(&mut self, n: usize) {
+        let others = self
+            .messages
+            .iter()
+            .filter(|message| !matches!(message.role, Role::System))
+            .count();
+        let mut to_drop = others.saturating_sub(n);
+        self.messages.retain(|message| {
+            if matches!(message.role, Role::System) || to_drop == 0 {
+                return true;
+            }
+            to_drop -= 1;
+            false
+        });
+    }
+
+    /// The last message is the one being answered, it is never dropped.
+    fn drop_oldest(&mut self) -> bool {
+        let last = self.messages.len().saturating_sub(1);
+        match self
+            .messages
+            .iter()
+            .take(last)
+            .position(|message| !matches!(message.role, Role::System))
+        {
+            Some(index) => {
+                self.messages.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn truncate_middle(
+        &mut self,
+        tokenizer: &Tokenizer,
+        excess: usize,
+    ) -> Result<bool, WavvyError> {
+        let mut longest: Option<(usize, Vec<u32>)> = None;
+        for (index, message) in self.messages.iter().enumerate() {
+            let ids = tokenizer
+                .encode(message.content.as_str(), false)
+                .map_err(WavvyError::TokenizerError)?
+                .get_ids()
+                .to_vec();
+            if longest
+                .as_ref()
+                .is_none_or(|(_, longest)| ids.len() > longest.len())
+            {
+                longest = Some((index, ids));
+            }
+        }
+        let Some((index, ids)) = longest else {
+            return Ok(false);
+        };
+        let marker_tokens = tokenizer
+            .encode(TRUNCATION_MARKER, false)
+            .map_err(WavvyError::TokenizerError)?
+            .len();
+        let keep = ids.len().saturating_sub(excess + marker_tokens);
+        if keep == 0 {
+            return Ok(false);
+        }
+
+        let head = keep.div_ceil(2);
+        let tail = keep - head;
+        let decode = |ids: &[u32]| {
+            tokenizer
+                .decode(ids, false)
+                .map_err(WavvyError::TokenizerError)
+        };
+        self.messages[index].content = format!(
+            "{}{TRUNCATION_MARKER}{}",
+            decode(&ids[..head])?,
+            decode(&ids[ids.len() - tail..])?
+        );
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokenizer() -> Tokenizer {
+        Tokenizer::from_file(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/tokenizer.json"
+        ))
+        .unwrap()
+    }
+
+    fn template(messages: Vec<(Role, &str)>) -> ChatTemplate {
+        let messages = messages
+            .into_iter()
+            .map(|(role, content)| Message::new(role, content.to_string()))
+            .collect();
+        ChatTemplate::new(Model::W, messages)
+    }
+
+    fn conversation() -> ChatTemplate {
+        template(vec![
+            (Role::System, "be brief"),
+            (Role::User, "hello world"),
+            (Role::Assistant, "hello"),
+            (Role::User, "the world is on"),
+        ])
+    }
+
+    fn contents(template: &ChatTemplate) -> Vec<&str> {
+        template
+            .messages
+            .iter()
+            .map(|message| message.content.as_str())
+            .collect()
+    }
+
+    fn is_overflow(result: Result<(), WavvyError>) -> bool {
+        matches!(result, Err(WavvyError::ContextOverflow { .. }))
+    }
+
+    #[test]
+    fn format_writes_each_role_once() {
+        let chat = template(vec![(Role::System, "be brief"), (Role::User, "hi")]);
+        assert_eq!(
+            chat.format(),
+            "<|im_start|>system\nbe brief<|im_end|>\n<|im_start|>user\nhi<|im_end|>\n<|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn token_breakdown_counts_each_message_once() -> Result<(), WavvyError> {
+        let tokenizer = tokenizer();
+        let empty = template(vec![(Role::User, "")]).count_tokens(&tokenizer)?;
+        let breakdown = template(vec![(Role::User, "hello world")]).token_breakdown(&tokenizer)?;
+        assert_eq!(breakdown.messages, vec![3]);
+        assert_eq!(breakdown.total, empty + 3);
+        assert_eq!(breakdown.template_overhead, empty);
+        Ok(())
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_system_prompt_and_the_last_message() -> Result<(), WavvyError> {
+        let tokenizer = tokenizer();
+        let max_tokens = template(vec![
+            (Role::System, "be brief"),
+            (Role::User, "the world is on"),
+        ])
+        .count_tokens(&tokenizer)?;
+
+        let mut chat = conversation();
+        chat.truncate(&tokenizer, max_tokens, &TruncationStrategy::DropOldest)?;
+        assert_eq!(contents(&chat), vec!["be brief", "the world is on"]);
+
+        let mut chat = conversation();
+        let result = chat.truncate(&tokenizer, max_tokens - 1, &TruncationStrategy::DropOldest);
+        assert!(is_overflow(result));
+        Ok(())
+    }
+
+    #[test]
+    fn keep_system_and_last_drops_older_messages() -> Result<(), WavvyError> {
+        let tokenizer = tokenizer();
+        let strategy = TruncationStrategy::KeepSystemAndLast(2);
+
+        let mut chat = conversation();
+        chat.truncate(&tokenizer, usize::MAX, &strategy)?;
+        assert_eq!(
+            contents(&chat),
+            vec!["be brief", "hello", "the world is on"]
+        );
+
+        let mut chat = conversation();
+        assert!(is_overflow(chat.truncate(&tokenizer, 1, &strategy)));
+        Ok(())
+    }
+
+    #[test]
+    fn truncate_middle_cuts_the_longest_message() -> Result<(), WavvyError> {
+        let tokenizer = tokenizer();
+        let long = "hello world ".repeat(20);
+        let mut chat = template(vec![
+            (Role::System, "be brief"),
+            (Role::User, long.trim_end()),
+        ]);
+        let max_tokens = chat.count_tokens(&tokenizer)? - 10;
+
+        chat.truncate(&tokenizer, max_tokens, &TruncationStrategy::TruncateMiddle)?;
+        assert!(chat.count_tokens(&tokenizer)? <= max_tokens);
+        assert_eq!(chat.messages[0].content, "be brief");
+        let content = &chat.messages[1].content;
+        assert!(content.starts_with("hello world"));
+        assert!(content.ends_with("hello world"));
+        assert!(content.contains(TRUNCATION_MARKER));
+        Ok(())
+    }
 
-        String::from_utf8(bytes).unwrap()
+    #[test]
+    fn truncate_middle_stops_when_nothing_is_left_to_cut() {
+        let tokenizer = tokenizer();
+        let mut chat = conversation();
+        let result = chat.truncate(&tokenizer, 1, &TruncationStrategy::TruncateMiddle);
+        assert!(is_overflow(result));
     }
 }
@@ -1,3 +1,4 @@
 pub mod chat_template;
 pub mod message;
 pub mod role;
+pub mod truncation;
@@ -0,0 +1,10 @@
+/// How `ChatTemplate::truncate` shortens a conversation that does not fit.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TruncationStrategy {
+    /// Drops the oldest turns after the system prompt, one at a time.
+    DropOldest,
+    /// Keeps the system messages and the last N other messages.
+    KeepSystemAndLast(usize),
+    /// Cuts tokens out of the middle of the longest message.
+    TruncateMiddle,
+}
@@ -0,0 +1,53 @@
+//! Export of the `tracing` spans to an OpenTelemetry collector, behind the
+//! `otel` feature.
+
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::{runtime, trace::TracerProvider, Resource};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+use crate::llm::wavvy_chat_stream::WavvyError;
+
+/// Flushes the spans not exported yet when dropped.
+pub struct TelemetryGuard {
+    provider: TracerProvider,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        let _ = self.provider.shutdown();
+    }
+}
+
+/// Installs the global subscriber, printing events to stderr and exporting
+/// spans over OTLP gRPC to `OTEL_EXPORTER_OTLP_ENDPOINT`, localhost by default.
+/// `RUST_LOG` filters both, `info` when unset. Must run inside a Tokio runtime.
+pub fn init(service_name: &str) -> Result<TelemetryGuard, WavvyError> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .build()
+        .map_err(|e| WavvyError::InvalidConfig {
+            context: String::from("OTLP exporter"),
+            source: e.into(),
+        })?;
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new([KeyValue::new(
+            "service.name",
+            service_name.to_string(),
+        )]))
+        .build();
+    let tracer = provider.tracer("wavvy-ai-sdk");
+
+    tracing_subscriber::registry()
+        .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .try_init()
+        .map_err(|e| WavvyError::InvalidConfig {
+            context: String::from("tracing subscriber"),
+            source: e.into(),
+        })?;
+    opentelemetry::global::set_tracer_provider(provider.clone());
+    Ok(TelemetryGuard { provider })
+}